@@ -97,6 +97,7 @@ module.exports = !global.ZeresPluginLibrary ? Dummy : (([Plugin, Api]) => {
   return class extends Plugin {
     onStart() {
       this.webSocket = new WebSocket("ws://127.0.0.1:9000");
+      this.parseData = this.parseData.bind(this);
       this.webSocket.onmessage = this.parseData;
       this.webSocket.onerror = (_) => {
         BdApi.showConfirmationModal("Tuxphones Daemon Error", [
@@ -242,32 +243,6 @@ module.exports = !global.ZeresPluginLibrary ? Dummy : (([Plugin, Api]) => {
         Logger.log("Secret key:");
         Logger.log(json.d.secret_key);
         this.secret_key = json.d.secret_key;
-        const op12 = {
-          "op": 12,
-          "d": {
-            "audio_ssrc": this.audio_ssrc,
-            "video_ssrc": this.video_ssrc,
-            "rtx_ssrc": this.video_ssrc + 1,
-            "streams": [
-              {
-                "type": "video",
-                "rid": "100",
-                "ssrc": this.video_ssrc,
-                "active": true,
-                "quality": 100,
-                "rtx_ssrc": this.video_ssrc + 1,
-                "max_bitrate": 8e6,
-                "max_framerate": this.selectedFPS,
-                "max_resolution": {
-                  "type": "fixed",
-                  "width": this.selectedResolution.width,
-                  "height": this.selectedResolution.height
-                }
-              }
-            ]
-          }
-        };
-        this._ws.send(JSON.stringify(op12));
         this.startStream(this.currentSoundProfile.pid, this.currentSoundProfile.xid, this.selectedResolution, this.selectedFPS, this.ip, this.port, this.secret_key, this.voice_ssrc, this.base_ssrc, this.audio_ssrc);
         return;
       } else if (json.op == 2) {
@@ -329,15 +304,88 @@ module.exports = !global.ZeresPluginLibrary ? Dummy : (([Plugin, Api]) => {
             apps: obj.apps
           });
           break;
+        case "AudioLevels":
+          Dispatcher.dispatch({
+            type: "TUX_AUDIO_LEVELS",
+            rms: obj.rms,
+            peak: obj.peak
+          });
+          break;
+        case "StreamStats":
+          Dispatcher.dispatch({
+            type: "TUX_STREAM_STATS",
+            stats: obj
+          });
+          break;
+        case "StreamStop":
+          Logger.log(`Daemon stopped the stream: ${obj.reason}`);
+          Dispatcher.dispatch({
+            type: "TUX_STREAM_STOP",
+            reason: obj.reason
+          });
+          break;
+        case "WindowList":
+          Dispatcher.dispatch({
+            type: "TUX_WINDOWS",
+            windows: obj.windows
+          });
+          break;
+        case "MonitorList":
+          Dispatcher.dispatch({
+            type: "TUX_MONITORS",
+            monitors: obj.monitors
+          });
+          break;
+        case "WindowPreview":
+          Dispatcher.dispatch({
+            type: "TUX_WINDOW_PREVIEW",
+            xid: obj.xid,
+            thumbnail: `data:${obj.mime_type};base64,${obj.data}`
+          });
+          break;
+        case "PipelineGraph":
+          Dispatcher.dispatch({
+            type: "TUX_PIPELINE_GRAPH",
+            dot: obj.dot,
+            svg: obj.svg
+          });
+          break;
         case "StreamPreview":
           Logger.log(this.streamKey);
           ChunkedRequests.makeChunkedRequest(`/streams/${this.streamKey}/preview`, {
-            thumbnail: `data:image/jpeg;base64,${obj.jpg}`
+            thumbnail: `data:${obj.mime_type};base64,${obj.data}`
           }, {
             method: "POST",
             token: AuthenticationStore.getToken()
           });
           break;
+        case "StreamLayers":
+          if (!this._ws || obj.rtc_connection_id !== RTCConnectionStore.getRTCConnectionId())
+            break;
+          this._ws.send(JSON.stringify({
+            op: 12,
+            d: {
+              audio_ssrc: obj.audio_ssrc,
+              video_ssrc: obj.layers[0].ssrc,
+              rtx_ssrc: obj.layers[0].rtx_ssrc,
+              streams: obj.layers.map((layer) => ({
+                type: "video",
+                rid: layer.rid,
+                ssrc: layer.ssrc,
+                active: true,
+                quality: layer.quality,
+                rtx_ssrc: layer.rtx_ssrc,
+                max_bitrate: layer.max_bitrate,
+                max_framerate: layer.max_framerate,
+                max_resolution: {
+                  type: layer.is_fixed ? "fixed" : "source",
+                  width: layer.width,
+                  height: layer.height
+                }
+              }))
+            }
+          }));
+          break;
         default:
           Logger.err(`Received unknown command type: ${obj.type}`);
       }
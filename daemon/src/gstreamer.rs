@@ -1,17 +1,29 @@
 use discordstreamer::discordstreamer::DiscordStreamer;
 use gst::prelude::*;
+use gst::subclass::prelude::ObjectSubclassIsExt;
 use gst::{
     debug_bin_to_dot_data, glib, DebugGraphDetails, Element, PadLinkError, StateChangeError,
     StateChangeSuccess,
 };
-use gst::subclass::prelude::ObjectSubclassIsExt;
 use image::EncodableLayout;
-use tracing::{debug, error, info, trace};
+use std::{
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use tokio::io::AsyncWriteExt;
+use tracing::{debug, error, info, trace, warn};
 use tracing_log::log::Level;
 
 use crate::{
-    socket::{StreamResolutionInformation},
-    xid,
+    socket::{
+        AudioLevels, AudioProfile, ContentHint, ExtraAudioSource, OpusAudioType, OpusOptions,
+        OverlayOptions, OverlayPosition, PrivacyMode, StreamLayer, StreamResolutionInformation,
+        StreamStats,
+    },
+    ssrc::StreamSSRCs,
+    stats::StatsCollector,
+    x::{CaptureSource, Rect},
 };
 
 #[derive(Debug)]
@@ -21,13 +33,6 @@ pub enum GstInitializationError {
     Pad(PadLinkError),
 }
 
-#[derive(Debug)]
-pub struct StreamSSRCs {
-    pub audio: u32,
-    pub video: u32,
-    pub rtx: u32,
-}
-
 impl std::fmt::Display for GstInitializationError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let str = match self {
@@ -39,6 +44,183 @@ impl std::fmt::Display for GstInitializationError {
     }
 }
 
+/// Opus encoder limits, frame sizes under 10ms are left out since they mostly add packet overhead
+const OPUS_BITRATE_RANGE: std::ops::RangeInclusive<i32> = 6000..=510000;
+const OPUS_FRAME_SIZES: [f64; 4] = [10.0, 20.0, 40.0, 60.0];
+const OPUS_COMPLEXITY_RANGE: std::ops::RangeInclusive<i32> = 0..=10;
+
+#[derive(Debug)]
+pub enum InvalidOpusSettings {
+    Bitrate(i32),
+    FrameSize(f64),
+    PacketLossPercentage(i32),
+    Complexity(i32),
+}
+
+impl std::fmt::Display for InvalidOpusSettings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let str = match self {
+            InvalidOpusSettings::Bitrate(b) => format!(
+                "Bitrate {} outside of {}-{}",
+                b,
+                OPUS_BITRATE_RANGE.start(),
+                OPUS_BITRATE_RANGE.end()
+            ),
+            InvalidOpusSettings::FrameSize(s) => {
+                format!("Frame size {}ms not one of {:?}", s, OPUS_FRAME_SIZES)
+            }
+            InvalidOpusSettings::PacketLossPercentage(p) => {
+                format!("Packet loss percentage {} outside of 0-100", p)
+            }
+            InvalidOpusSettings::Complexity(c) => format!(
+                "Complexity {} outside of {}-{}",
+                c,
+                OPUS_COMPLEXITY_RANGE.start(),
+                OPUS_COMPLEXITY_RANGE.end()
+            ),
+        };
+        f.write_str(&str)
+    }
+}
+
+/// Resolved `opusenc` settings
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OpusSettings {
+    pub bitrate: i32,
+    pub audio_type: OpusAudioType,
+    /// Frame size in milliseconds
+    pub frame_size: f64,
+    pub fec: bool,
+    pub packet_loss_percentage: i32,
+    pub dtx: bool,
+    pub complexity: i32,
+}
+
+impl From<AudioProfile> for OpusSettings {
+    fn from(profile: AudioProfile) -> Self {
+        match profile {
+            AudioProfile::Voice => OpusSettings {
+                bitrate: 64000,
+                audio_type: OpusAudioType::Voice,
+                frame_size: 20.0,
+                fec: true,
+                packet_loss_percentage: 20,
+                dtx: true,
+                complexity: 10,
+            },
+            AudioProfile::Balanced => OpusSettings {
+                bitrate: 128000,
+                audio_type: OpusAudioType::Generic,
+                frame_size: 20.0,
+                fec: true,
+                packet_loss_percentage: 10,
+                dtx: false,
+                complexity: 10,
+            },
+            AudioProfile::Music => OpusSettings {
+                bitrate: 256000,
+                audio_type: OpusAudioType::Generic,
+                frame_size: 20.0,
+                fec: true,
+                packet_loss_percentage: 5,
+                dtx: false,
+                complexity: 10,
+            },
+        }
+    }
+}
+
+impl OpusSettings {
+    /// Applies overrides to a profile's settings and checks the result
+    pub fn resolve(
+        profile: AudioProfile,
+        options: &OpusOptions,
+    ) -> Result<Self, InvalidOpusSettings> {
+        let defaults = OpusSettings::from(profile);
+        let settings = OpusSettings {
+            bitrate: options.bitrate.unwrap_or(defaults.bitrate),
+            audio_type: options.audio_type.unwrap_or(defaults.audio_type),
+            frame_size: options.frame_size.unwrap_or(defaults.frame_size),
+            fec: options.fec.unwrap_or(defaults.fec),
+            packet_loss_percentage: options
+                .packet_loss_percentage
+                .unwrap_or(defaults.packet_loss_percentage),
+            dtx: options.dtx.unwrap_or(defaults.dtx),
+            complexity: options.complexity.unwrap_or(defaults.complexity),
+        };
+
+        if !OPUS_BITRATE_RANGE.contains(&settings.bitrate) {
+            return Err(InvalidOpusSettings::Bitrate(settings.bitrate));
+        }
+        if !OPUS_FRAME_SIZES.contains(&settings.frame_size) {
+            return Err(InvalidOpusSettings::FrameSize(settings.frame_size));
+        }
+        if !(0..=100).contains(&settings.packet_loss_percentage) {
+            return Err(InvalidOpusSettings::PacketLossPercentage(
+                settings.packet_loss_percentage,
+            ));
+        }
+        if !OPUS_COMPLEXITY_RANGE.contains(&settings.complexity) {
+            return Err(InvalidOpusSettings::Complexity(settings.complexity));
+        }
+
+        Ok(settings)
+    }
+}
+
+/// Encoder and scaling trade-offs for a kind of content
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VideoTuning {
+    /// Slower x264 presets look sharper at the same bitrate
+    pub x264_preset: &'static str,
+    pub x264_tune: &'static str,
+    /// `None` keeps nvh264enc's default preset
+    pub nvenc_preset: Option<&'static str>,
+    /// libvpx speed, further from 0 is faster
+    pub vpx_cpu_used: i32,
+    /// Seconds between keyframes, shorter recovers faster from packet loss.
+    /// `None` keeps the long H.264 GOP of `DEFAULT_KEYFRAME_INTERVAL` frames and the VPx encoders' defaults
+    pub keyframe_interval: Option<u32>,
+    /// `videoscale` method
+    pub scale_method: &'static str,
+    /// Upper bound on the requested framerate
+    pub max_fps: i32,
+}
+
+impl From<ContentHint> for VideoTuning {
+    fn from(hint: ContentHint) -> Self {
+        match hint {
+            ContentHint::Motion => VideoTuning {
+                x264_preset: "ultrafast",
+                x264_tune: "zerolatency",
+                nvenc_preset: None,
+                vpx_cpu_used: -16,
+                keyframe_interval: None,
+                scale_method: "bilinear",
+                max_fps: i32::MAX,
+            },
+            ContentHint::Detail => VideoTuning {
+                x264_preset: "veryfast",
+                x264_tune: "zerolatency",
+                nvenc_preset: Some("low-latency-hq"),
+                vpx_cpu_used: -8,
+                keyframe_interval: Some(15),
+                scale_method: "lanczos",
+                max_fps: 30,
+            },
+            ContentHint::Text => VideoTuning {
+                x264_preset: "faster",
+                x264_tune: "zerolatency+stillimage",
+                nvenc_preset: Some("low-latency-hq"),
+                vpx_cpu_used: -5,
+                keyframe_interval: Some(30),
+                scale_method: "lanczos",
+                max_fps: 15,
+            },
+        }
+    }
+}
+
 #[derive(Clone, Copy)]
 pub struct H264Settings {
     pub nvidia_encoder: bool,
@@ -70,20 +252,118 @@ impl From<PadLinkError> for GstInitializationError {
     }
 }
 
+/// Stream-only volume settings for a captured application, independent of what the user hears locally
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AudioMix {
+    /// Linear gain, 1.0 leaves the audio unchanged
+    pub gain: f64,
+    pub mute: bool,
+}
+
+impl Default for AudioMix {
+    fn default() -> Self {
+        AudioMix {
+            gain: 1.0,
+            mute: false,
+        }
+    }
+}
+
+/// Lowers captured audio while the user is speaking in voice chat
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DuckingSettings {
+    pub enabled: bool,
+    /// Linear gain applied on top of the mix gain while ducked
+    pub level: f64,
+}
+
+impl Default for DuckingSettings {
+    fn default() -> Self {
+        DuckingSettings {
+            enabled: false,
+            level: 0.3,
+        }
+    }
+}
+
+/// How the mouse is shown in the stream
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CursorSettings {
+    pub show: bool,
+    pub highlight_clicks: bool,
+}
+
+/// Ring drawn around clicks, centered on the click position
+const CLICK_RING_SIZE: i32 = 48;
+const CLICK_RING_SVG: &str = r##"<svg xmlns="http://www.w3.org/2000/svg" width="48" height="48"><circle cx="24" cy="24" r="20" fill="none" stroke="#ffd400" stroke-opacity="0.9" stroke-width="4"/></svg>"##;
+/// Nothing to draw, rsvgoverlay needs a valid document at all times
+const EMPTY_SVG: &str = r#"<svg xmlns="http://www.w3.org/2000/svg" width="1" height="1"/>"#;
+
+/// Distance of overlays from the edges of the video
+const OVERLAY_MARGIN: i32 = 16;
+
+/// Raw frames that can wait for the video encoder, older ones are dropped so latency can't build up
+const MAX_QUEUED_FRAMES: u32 = 2;
+/// Raw audio that can wait for the audio encoder before the oldest is dropped
+const MAX_QUEUED_AUDIO_MS: u64 = 200;
+/// Encoded data that can wait for the streamer, a full queue holds back the encoders instead
+const MAX_QUEUED_ENCODED_MS: u64 = 500;
+
+/// Frames between H.264 keyframes when the tuning doesn't ask for an interval
+const DEFAULT_KEYFRAME_INTERVAL: u32 = 2560;
+
+/// Blurred copy of part of the frame, composited over the original
+struct PrivacyBlur {
+    crop: Element,
+    /// Compositor input of the blurred copy
+    pad: gst::Pad,
+    /// Set while the whole frame is blurred because the regions couldn't be positioned yet
+    pending: AtomicBool,
+}
+
+/// Events from the pipeline bus that the command processor acts on
+#[derive(Debug)]
+pub enum PipelineEvent {
+    AudioLevels(AudioLevels),
+    /// An element failed, the pipeline stops running
+    Error(String),
+    /// The pipeline ran out of data
+    Eos,
+}
+
 pub struct GstHandle {
     pipeline: gst::Pipeline,
     encoder: Element,
     encoder_type: VideoEncoderType,
+    volume: Element,
+    video_selector: Element,
+    live_video_pad: gst::Pad,
+    placeholder_video_pad: gst::Pad,
+    paused_video_pad: gst::Pad,
+    audio_selector: Element,
+    live_audio_pad: gst::Pad,
+    silent_audio_pad: gst::Pad,
+    /// Whether the capture should be replaced by the placeholder (hidden or shielded window)
+    placeholder_shown: AtomicBool,
+    paused: AtomicBool,
+    /// Always present so text can be added mid-stream
+    text_overlay: Element,
+    /// Draws click highlights, only present when enabled
+    click_overlay: Option<Element>,
+    /// Only present in blur privacy mode
+    privacy_blur: Option<PrivacyBlur>,
+    /// Where pipeline graphs are written on state changes and errors, disabled if `None`
+    graph_dir: Option<PathBuf>,
+    stats: StatsCollector,
+    /// Video layers actually built, full size first
+    layers: Vec<StreamLayer>,
 }
 
 //Custom drop logic to deinit gstreamer when all handles are dropped
 impl Drop for GstHandle {
     fn drop(&mut self) {
         info!("dropping GstHandle");
-        // Debug diagram
-        let out = debug_bin_to_dot_data(&self.pipeline, DebugGraphDetails::ALL);
-        //TODO: Move to logs folder
-        std::fs::write("/tmp/tuxphones_gstdrop.dot", out.as_str()).unwrap();
+        self.dump_graph("dropped");
 
         if let Err(e) = self.pipeline.set_state(gst::State::Null) {
             error!("Failed to stop pipeline: {:?}", e);
@@ -94,12 +374,20 @@ impl Drop for GstHandle {
 impl GstHandle {
     pub async fn new(
         encoder_to_use: VideoEncoderType,
-        xid: xid,
+        capture_source: CaptureSource,
         resolution: StreamResolutionInformation,
         fps: i32,
+        tuning: VideoTuning,
+        video_bitrate: u32,
         secret_key: Vec<u8>,
-        base_ssrc: u32,
+        ssrcs: StreamSSRCs,
         address: String,
+        extra_source: Option<ExtraAudioSource>,
+        opus: OpusSettings,
+        cursor: CursorSettings,
+        privacy_mode: PrivacyMode,
+        overlay: OverlayOptions,
+        graph_dir: Option<PathBuf>,
     ) -> Result<Self, GstInitializationError> {
         info!("Creating new GstHandle");
         //Create a new GStreamer pipeline
@@ -108,15 +396,53 @@ impl GstHandle {
         //--VIDEO--
 
         //Create a new ximagesrc to get video from the X server
-        let ximagesrc = ximageredux::XImageRedux::default();
+        let ximagesrc: Element = match capture_source {
+            CaptureSource::Window(xid) => {
+                let ximagesrc = ximageredux::XImageRedux::default();
+                if !cursor.show || ximagesrc.find_property("show-pointer").is_some() {
+                    //Set xid based on constructor parameter to get video only from the specified X window
+                    ximagesrc.set_property("xid", xid as u32);
+                    if cursor.show {
+                        ximagesrc.set_property_from_str("show-pointer", "true");
+                    }
+                    ximagesrc.upcast()
+                } else {
+                    //The stock ximagesrc composites the cursor through XFixes, but doesn't follow resizes
+                    warn!("XImageRedux can't draw the cursor, capturing window with ximagesrc");
+                    let ximagesrc = gst::ElementFactory::make("ximagesrc").build()?;
+                    ximagesrc.set_property("xid", xid as u64);
+                    ximagesrc.set_property("show-pointer", true);
+                    ximagesrc.set_property("use-damage", false);
+                    ximagesrc
+                }
+            }
+            CaptureSource::Area(area) => {
+                //The stock ximagesrc can capture part of the root window, end coordinates are inclusive
+                let ximagesrc = gst::ElementFactory::make("ximagesrc").build()?;
+                ximagesrc.set_property("startx", area.x as u32);
+                ximagesrc.set_property("starty", area.y as u32);
+                ximagesrc.set_property("endx", (area.x as u32 + area.width as u32) - 1);
+                ximagesrc.set_property("endy", (area.y as u32 + area.height as u32) - 1);
+                ximagesrc.set_property("use-damage", false);
+                ximagesrc.set_property("show-pointer", cursor.show);
+                ximagesrc
+            }
+        };
+
+        let fps = fps.min(tuning.max_fps);
+        let keyframe_interval = tuning.keyframe_interval.map(|secs| fps as u32 * secs);
+
+        //Paces the video to the requested fps, dropping or repeating frames as needed
+        let videorate = gst::ElementFactory::make("videorate").build()?;
 
         let videoscale = gst::ElementFactory::make("videoscale").build()?;
+        videoscale.set_property_from_str("method", tuning.scale_method);
 
         //Creating a capsfilter to set the resolution and the fps
         let capsfilter = gst::ElementFactory::make("capsfilter").build()?;
 
-        let mut cap = gst::Caps::builder("video/x-raw")
-            .field("frame_rate", gst::Fraction::new(fps, 1));
+        let mut cap =
+            gst::Caps::builder("video/x-raw").field("framerate", gst::Fraction::new(fps, 1));
 
         //If the resolution is specified, add it to the caps
         if resolution.is_fixed {
@@ -125,80 +451,78 @@ impl GstHandle {
                 .field("height", resolution.height as i32);
         };
 
-        capsfilter.set_property(
-            "caps",
-            &cap.build(),
-        );
+        capsfilter.set_property("caps", &cap.build());
 
-        // ximagesrc.set_property_from_str("show-pointer", "1");
-        //Set xid based on constructor parameter to get video only from the specified X window
-        ximagesrc.set_property("xid", xid as u32);
+        //Switches to a placeholder while the window can't be captured (minimized, unmapped)
+        let video_selector = gst::ElementFactory::make("input-selector").build()?;
+        let placeholder = gst::ElementFactory::make("videotestsrc").build()?;
+        placeholder.set_property_from_str("pattern", "black");
+        placeholder.set_property("is-live", true);
+        //Shown instead of everything else while the stream is paused
+        let paused_source = gst::ElementFactory::make("videotestsrc").build()?;
+        paused_source.set_property_from_str("pattern", "black");
+        paused_source.set_property("is-live", true);
+        let paused_text = gst::ElementFactory::make("textoverlay").build()?;
+        paused_text.set_property("text", "Paused");
+        paused_text.set_property("font-desc", "Sans 48");
+        paused_text.set_property_from_str("valignment", "center");
+        paused_text.set_property_from_str("halignment", "center");
 
         //Create a new videoconvert to allow encoding of the raw video
         let videoconvert = gst::ElementFactory::make("videoconvert").build()?;
 
-        //Chose encoder based on constructor params
-        let encoder = match encoder_to_use {
-            VideoEncoderType::H264(settings) => {
-                //Use nvidia encoder based on settings
-                if settings.nvidia_encoder {
-                    let nvh264enc = gst::ElementFactory::make("nvh264enc").build()?;
-                    nvh264enc.set_property("gop-size", 2560i32);
-                    nvh264enc.set_property_from_str("rc-mode", "cbr-ld-hq");
-                    nvh264enc.set_property("zerolatency", true);
-                    nvh264enc
-                } else {
-                    let x264enc = gst::ElementFactory::make("x264enc").build()?;
-                    x264enc.set_property("threads", 12u32);
-                    x264enc.set_property_from_str("tune", "zerolatency");
-                    x264enc.set_property_from_str("speed-preset", "ultrafast");
-                    x264enc.set_property("key-int-max", 2560u32);
-                    x264enc.set_property("b-adapt", false);
-                    x264enc.set_property("vbv-buf-capacity", 120u32);
-                    x264enc
+        //Overlays are drawn at the output resolution so their size doesn't depend on the capture
+        let mut overlays = vec![];
+        let text_overlay = gst::ElementFactory::make("textoverlay").build()?;
+        text_overlay.set_property("font-desc", overlay.font.as_str());
+        set_text_position(&text_overlay, overlay.text_position);
+        match &overlay.text {
+            Some(text) => text_overlay.set_property("text", text.as_str()),
+            None => text_overlay.set_property("silent", true),
+        }
+        overlays.push(text_overlay.clone());
+
+        if overlay.clock {
+            let clock_overlay = gst::ElementFactory::make("clockoverlay").build()?;
+            clock_overlay.set_property("font-desc", overlay.font.as_str());
+            clock_overlay.set_property("time-format", "%H:%M");
+            set_text_position(&clock_overlay, overlay.clock_position);
+            overlays.push(clock_overlay);
+        }
+
+        if let Some(path) = &overlay.image {
+            let image_overlay = gst::ElementFactory::make("gdkpixbufoverlay").build()?;
+            image_overlay.set_property("location", path.as_str());
+            image_overlay.set_property("alpha", overlay.image_alpha.clamp(0.0, 1.0));
+            //Reads the image header from disk, kept off the command loop's thread
+            let image_path = path.clone();
+            match tokio::task::spawn_blocking(move || image::image_dimensions(image_path)).await {
+                Ok(Ok((width, height))) => {
+                    set_image_position(&image_overlay, overlay.image_position, width, height)
                 }
+                Ok(Err(e)) => error!("Failed to read overlay image {}: {}", path, e),
+                Err(e) => error!("Overlay image size task failed: {}", e),
             }
-            VideoEncoderType::VP8 => {
-                let vp8enc = gst::ElementFactory::make("vp8enc").build()?;
-                vp8enc.set_property("threads", 12i32);
-                vp8enc.set_property("cpu-used", -16i32);
-                vp8enc.set_property_from_str("end-usage", "cbr");
-                vp8enc.set_property("buffer-initial-size", 100i32);
-                vp8enc.set_property("buffer-optimal-size", 120i32);
-                vp8enc.set_property("buffer-size", 150i32);
-                vp8enc.set_property("max-intra-bitrate", 250i32);
-                vp8enc.set_property_from_str("error-resilient", "default");
-                vp8enc.set_property("lag-in-frames", 0i32);
-                vp8enc
-            }
-            VideoEncoderType::VP9 => {
-                let vp9enc = gst::ElementFactory::make("vp9enc").build()?;
-                vp9enc.set_property("threads", 12i32);
-                vp9enc.set_property("cpu-used", -16i32);
-                vp9enc.set_property_from_str("end-usage", "cbr");
-                vp9enc.set_property("buffer-initial-size", 100i32);
-                vp9enc.set_property("buffer-optimal-size", 120i32);
-                vp9enc.set_property("buffer-size", 150i32);
-                vp9enc.set_property("max-intra-bitrate", 250i32);
-                vp9enc.set_property_from_str("error-resilient", "default");
-                vp9enc.set_property("lag-in-frames", 0i32);
-                vp9enc
-            }
-        };
+            overlays.push(image_overlay);
+        }
+
+        //Overlays may output formats the encoder can't take
+        let overlay_convert = gst::ElementFactory::make("videoconvert").build()?;
+
+        let encoder =
+            make_video_encoder(encoder_to_use, &tuning, keyframe_interval, video_bitrate)?;
 
         //--AUDIO--
 
-        // Caps filter for audio from conversion to encoding
+        //Caps filter for audio from conversion to encoding
         let audio_capsfilter = gst::ElementFactory::make("capsfilter").build()?;
 
         let cap = gst::Caps::builder("audio/x-raw")
             .field("channels", 2)
             .field("rate", 48000);
 
-        audio_capsfilter.set_property(
-            "caps",
-            &cap.build(),
-        );
+        let cap = cap.build();
+        audio_capsfilter.set_property("caps", &cap);
 
         //Create a new pulsesrc to get audio from the PulseAudio server
         let pulsesrc = gst::ElementFactory::make("pulsesrc").build()?;
@@ -207,93 +531,1037 @@ impl GstHandle {
 
         //Create a new audioconvert to allow encoding of the raw audio
         let audioconvert = gst::ElementFactory::make("audioconvert").build()?;
+        //Stream-only gain and mute, local playback is untouched
+        let volume = gst::ElementFactory::make("volume").build()?;
+        //Mixes the captured application with an optional extra source
+        let audiomixer = gst::ElementFactory::make("audiomixer").build()?;
+        let mix_capsfilter = gst::ElementFactory::make("capsfilter").build()?;
+        mix_capsfilter.set_property("caps", &cap);
+        //Level metering of the final mix, posts a message on the bus every interval
+        let level = gst::ElementFactory::make("level").build()?;
+        level.set_property("interval", gst::ClockTime::from_mseconds(100).nseconds());
+        level.set_property("post-messages", true);
         //Encoder for the raw audio to opus
         let opusenc = gst::ElementFactory::make("opusenc").build()?;
-        opusenc.set_property("bitrate", 32000i32);
+        opusenc.set_property("bitrate", opus.bitrate);
         opusenc.set_property_from_str("bitrate-type", "cbr");
-        opusenc.set_property("inband-fec", true);
-        opusenc.set_property("packet-loss-percentage", 50);
+        opusenc.set_property_from_str(
+            "audio-type",
+            match opus.audio_type {
+                OpusAudioType::Generic => "generic",
+                OpusAudioType::Voice => "voice",
+            },
+        );
+        //Enum nicks are the frame size in milliseconds
+        opusenc.set_property_from_str("frame-size", &opus.frame_size.to_string());
+        opusenc.set_property("inband-fec", opus.fec);
+        opusenc.set_property("packet-loss-percentage", opus.packet_loss_percentage);
+        opusenc.set_property("dtx", opus.dtx);
+        opusenc.set_property("complexity", opus.complexity);
+        trace!("Opus settings: {:?}", opus);
+
+        //Switches to silence while the stream is paused
+        let audio_selector = gst::ElementFactory::make("input-selector").build()?;
+        let silence = gst::ElementFactory::make("audiotestsrc").build()?;
+        silence.set_property_from_str("wave", "silence");
+        silence.set_property("is-live", true);
+        let silence_capsfilter = gst::ElementFactory::make("capsfilter").build()?;
+        silence_capsfilter.set_property("caps", &cap);
 
         //DESTINATION
         let discord_streamer = DiscordStreamer::default();
-        let video_ssrc = base_ssrc;
-        let audio_ssrc = base_ssrc + 1;
         discord_streamer.set_property("crypto-key", glib::Bytes::from(secret_key.as_bytes()));
         discord_streamer.set_property("address", address.to_value());
-        discord_streamer.set_property("video-ssrc", video_ssrc.to_value());
-        discord_streamer.set_property("audio-ssrc", audio_ssrc.to_value());
+        discord_streamer.set_property("video-ssrc", ssrcs.video.to_value());
+        discord_streamer.set_property("audio-ssrc", ssrcs.audio.to_value());
+        //Lets viewers recover lost video packets instead of waiting for the next keyframe
+        if discord_streamer.find_property("rtx-ssrc").is_some() {
+            discord_streamer.set_property("rtx-ssrc", ssrcs.rtx.to_value());
+        } else {
+            warn!("DiscordStreamer doesn't support RTX, lost video packets won't be retransmitted");
+        }
         debug!("DiscordStreamer created");
         trace!("DiscordStreamer address: {:?}", address);
-        trace!("DiscordStreamer video-ssrc: {:?}", base_ssrc);
-        trace!("DiscordStreamer audio-ssrc: {:?}", base_ssrc+1);
+        trace!("DiscordStreamer SSRCs: {:?}", ssrcs);
         trace!("DiscordStreamer crypto-key: {:?}", secret_key);
 
-
-        //queues
-        let video_encoder_queue = gst::ElementFactory::make("queue").build()?;
+        //queues, raw data is dropped when the encoders fall behind, encoded data is never dropped
+        let video_encoder_queue = make_frame_queue()?;
         let audio_encoder_queue = gst::ElementFactory::make("queue").build()?;
-        let video_webrtc_queue = gst::ElementFactory::make("queue").build()?;
-        let audio_webrtc_queue = gst::ElementFactory::make("queue").build()?;
+        audio_encoder_queue.set_property_from_str("leaky", "downstream");
+        audio_encoder_queue.set_property("max-size-buffers", 0u32);
+        audio_encoder_queue.set_property("max-size-bytes", 0u32);
+        audio_encoder_queue.set_property(
+            "max-size-time",
+            gst::ClockTime::from_mseconds(MAX_QUEUED_AUDIO_MS).nseconds(),
+        );
+        let video_webrtc_queue = make_encoded_queue()?;
+        let audio_webrtc_queue = make_encoded_queue()?;
 
         //Add elements to the pipeline
         pipeline.add_many(&[
-            ximagesrc.upcast_ref::<Element>(),
+            &ximagesrc,
+            &placeholder,
+            &paused_source,
+            &paused_text,
+            &video_selector,
+            &videorate,
             &videoscale,
             &capsfilter,
             &videoconvert,
+            &overlay_convert,
             &encoder,
             &video_encoder_queue,
             &video_webrtc_queue,
-            /*&pulsesrc,
+            &pulsesrc,
             &audioconvert,
+            &volume,
+            &audiomixer,
+            &mix_capsfilter,
+            &audio_selector,
+            &silence,
+            &silence_capsfilter,
+            &level,
             &audio_capsfilter,
             &opusenc,
             &audio_encoder_queue,
             &audio_webrtc_queue,
-             */
             discord_streamer.upcast_ref::<Element>(),
         ])?;
 
         //Link video elements
-        Element::link_many(&[
-            ximagesrc.upcast_ref::<Element>(),
+        let live_video_pad = link_to_new_input(&ximagesrc, &video_selector)?;
+        let placeholder_capsfilter = add_filler_conversion(&pipeline, &placeholder)?;
+        let placeholder_video_pad = link_to_new_input(&placeholder_capsfilter, &video_selector)?;
+        paused_source.link(&paused_text)?;
+        let paused_capsfilter = add_filler_conversion(&pipeline, &paused_text)?;
+        let paused_video_pad = link_to_new_input(&paused_capsfilter, &video_selector)?;
+        video_selector.set_property("active-pad", &live_video_pad);
+
+        //Fillers follow the capture's size and format, switching inputs would otherwise renegotiate the encoder
+        let filler_capsfilters = vec![placeholder_capsfilter, paused_capsfilter];
+        live_video_pad.add_probe(gst::PadProbeType::EVENT_DOWNSTREAM, move |_, info| {
+            if let Some(gst::PadProbeData::Event(ref event)) = info.data {
+                if let gst::EventView::Caps(caps) = event.view() {
+                    if let Some(caps) = filler_caps(caps.caps()) {
+                        for capsfilter in &filler_capsfilters {
+                            capsfilter.set_property("caps", &caps);
+                        }
+                    }
+                }
+            }
+            gst::PadProbeReturn::Ok
+        });
+
+        //Optional stages between the selector and scaling, each linked after the previous one
+        let mut video_tail = video_selector.clone();
+
+        //Protected windows are blurred by cropping a copy of the frame to them and compositing it back
+        let privacy_blur = if privacy_mode == PrivacyMode::Blur {
+            let privacy_convert = gst::ElementFactory::make("videoconvert").build()?;
+            let privacy_tee = gst::ElementFactory::make("tee").build()?;
+            let clear_queue = gst::ElementFactory::make("queue").build()?;
+            let blur_queue = gst::ElementFactory::make("queue").build()?;
+            let crop = gst::ElementFactory::make("videocrop").build()?;
+            let blur_convert = gst::ElementFactory::make("videoconvert").build()?;
+            let blur = gst::ElementFactory::make("gaussianblur").build()?;
+            blur.set_property("sigma", 20f64);
+            let blur_out_convert = gst::ElementFactory::make("videoconvert").build()?;
+            let compositor = gst::ElementFactory::make("compositor").build()?;
+            compositor.set_property_from_str("background", "black");
+
+            pipeline.add_many(&[
+                &privacy_convert,
+                &privacy_tee,
+                &clear_queue,
+                &blur_queue,
+                &crop,
+                &blur_convert,
+                &blur,
+                &blur_out_convert,
+                &compositor,
+            ])?;
+            Element::link_many(&[&video_tail, &privacy_convert, &privacy_tee, &clear_queue])?;
+            Element::link_many(&[
+                &privacy_tee,
+                &blur_queue,
+                &crop,
+                &blur_convert,
+                &blur,
+                &blur_out_convert,
+            ])?;
+
+            let clear_pad = link_to_new_input(&clear_queue, &compositor)?;
+            clear_pad.set_property("zorder", 0u32);
+            let blur_pad = link_to_new_input(&blur_out_convert, &compositor)?;
+            blur_pad.set_property("zorder", 1u32);
+            blur_pad.set_property("alpha", 0f64);
+
+            video_tail = compositor;
+            Some(PrivacyBlur {
+                crop,
+                pad: blur_pad,
+                pending: AtomicBool::new(false),
+            })
+        } else {
+            None
+        };
+
+        //Click highlights are drawn at capture resolution, before scaling
+        let click_overlay = if cursor.highlight_clicks {
+            let overlay_convert = gst::ElementFactory::make("videoconvert").build()?;
+            let overlay = gst::ElementFactory::make("rsvgoverlay").build()?;
+            overlay.set_property("data", EMPTY_SVG);
+
+            pipeline.add_many(&[&overlay_convert, &overlay])?;
+            Element::link_many(&[&video_tail, &overlay_convert, &overlay])?;
+            video_tail = overlay.clone();
+            Some(overlay)
+        } else {
+            None
+        };
+
+        pipeline.add_many(&overlays.iter().collect::<Vec<_>>())?;
+
+        let mut video_chain = vec![
+            &video_tail,
+            &videorate,
             &videoscale,
             &capsfilter,
             &videoconvert,
+        ];
+        video_chain.extend(overlays.iter());
+        video_chain.push(&overlay_convert);
+        Element::link_many(&video_chain)?;
+
+        //Smaller copies of the video for viewers with less bandwidth, each with its own encoder
+        let layers = match ssrcs.layers {
+            1 => 1,
+            _ if discord_streamer.pad_template("video_%u").is_none() => {
+                warn!("DiscordStreamer can't take simulcast layers, sending a single layer");
+                1
+            }
+            layers => layers,
+        };
+        let video_head = match layers {
+            1 => overlay_convert.clone(),
+            _ => {
+                let tee = gst::ElementFactory::make("tee").build()?;
+                pipeline.add(&tee)?;
+                overlay_convert.link(&tee)?;
+                tee
+            }
+        };
+        Element::link_many(&[
+            &video_head,
             &video_encoder_queue,
             &encoder,
             &video_webrtc_queue,
             discord_streamer.upcast_ref::<Element>(),
         ])?;
 
-/*        //Link audio elements
+        let mut stream_layers = vec![stream_layer(0, ssrcs, video_bitrate, fps, &resolution)];
+        let mut layer_capsfilters = vec![];
+        let mut layer_encoders = vec![];
+        for layer in 1..layers {
+            let layer_queue = make_frame_queue()?;
+            let layer_scale = gst::ElementFactory::make("videoscale").build()?;
+            layer_scale.set_property_from_str("method", tuning.scale_method);
+            //Caps are set once the full size is known
+            let layer_capsfilter = gst::ElementFactory::make("capsfilter").build()?;
+            //Each layer halves the size, so it needs about a quarter of the bits
+            let layer_bitrate = video_bitrate / 4u32.pow(layer as u32);
+            let layer_encoder =
+                make_video_encoder(encoder_to_use, &tuning, keyframe_interval, layer_bitrate)?;
+            let layer_webrtc_queue = make_encoded_queue()?;
+
+            pipeline.add_many(&[
+                &layer_queue,
+                &layer_scale,
+                &layer_capsfilter,
+                &layer_encoder,
+                &layer_webrtc_queue,
+            ])?;
+            Element::link_many(&[
+                &video_head,
+                &layer_queue,
+                &layer_scale,
+                &layer_capsfilter,
+                &layer_encoder,
+                &layer_webrtc_queue,
+            ])?;
+
+            let streamer_pad = discord_streamer
+                .request_pad_simple("video_%u")
+                .ok_or_else(|| glib::bool_error!("Failed to request DiscordStreamer video pad"))?;
+            //Without its own SSRC a layer would be sent as the full-size video
+            if streamer_pad.find_property("ssrc").is_none() {
+                return Err(
+                    glib::bool_error!("DiscordStreamer video pads can't take an SSRC").into(),
+                );
+            }
+            let layer_ssrcs = ssrcs.layer(layer);
+            streamer_pad.set_property("ssrc", layer_ssrcs.video);
+            if streamer_pad.find_property("rtx-ssrc").is_some() {
+                streamer_pad.set_property("rtx-ssrc", layer_ssrcs.rtx);
+            }
+            layer_webrtc_queue
+                .static_pad("src")
+                .ok_or_else(|| glib::bool_error!("queue has no src pad"))?
+                .link(&streamer_pad)?;
+
+            debug!(
+                "Simulcast layer {} added with SSRCs {:?}",
+                layer, layer_ssrcs
+            );
+            stream_layers.push(stream_layer(layer, ssrcs, layer_bitrate, fps, &resolution));
+            layer_capsfilters.push((layer, layer_capsfilter));
+            layer_encoders.push(layer_encoder);
+        }
+
+        //Sizes the layers from the full video whenever it changes
+        if !layer_capsfilters.is_empty() {
+            let tee_sink = video_head
+                .static_pad("sink")
+                .ok_or_else(|| glib::bool_error!("tee has no sink pad"))?;
+            tee_sink.add_probe(gst::PadProbeType::EVENT_DOWNSTREAM, move |_, info| {
+                if let Some(gst::PadProbeData::Event(ref event)) = info.data {
+                    if let gst::EventView::Caps(caps) = event.view() {
+                        let size = caps.caps().structure(0).and_then(|s| {
+                            Some((s.get::<i32>("width").ok()?, s.get::<i32>("height").ok()?))
+                        });
+                        if let Some((width, height)) = size {
+                            for (layer, capsfilter) in &layer_capsfilters {
+                                //Encoders need even sizes
+                                let caps = gst::Caps::builder("video/x-raw")
+                                    .field("width", ((width >> layer) & !1).max(2))
+                                    .field("height", ((height >> layer) & !1).max(2))
+                                    .build();
+                                capsfilter.set_property("caps", &caps);
+                            }
+                        }
+                    }
+                }
+                gst::PadProbeReturn::Ok
+            });
+        }
+
+        //Link audio elements
+        //The tuxphones sink exists before the pipeline is built since StartStream sets up Pulse capture
+        //first, and a failing audio branch posts a bus error that stops the stream instead of hanging it
         Element::link_many(&[
             &pulsesrc,
             &audio_capsfilter,
             &audioconvert,
+            &volume,
+            &audiomixer,
+            &mix_capsfilter,
+        ])?;
+        let live_audio_pad = link_to_new_input(&mix_capsfilter, &audio_selector)?;
+        silence.link(&silence_capsfilter)?;
+        let silent_audio_pad = link_to_new_input(&silence_capsfilter, &audio_selector)?;
+        audio_selector.set_property("active-pad", &live_audio_pad);
+        Element::link_many(&[
+            &audio_selector,
+            &level,
             &audio_encoder_queue,
             &opusenc,
             &audio_webrtc_queue,
             discord_streamer.upcast_ref::<Element>(),
         ])?;
-*/
-        // Debug diagram
-        let out = debug_bin_to_dot_data(&pipeline, DebugGraphDetails::ALL);
-        //TODO: Move to logs folder
-        std::fs::write("/tmp/tuxphones_gst.dot", out.as_str()).unwrap();
+
+        //Mix in another Pulse source (microphone, another monitor) with its own gain
+        if let Some(extra_source) = extra_source {
+            let extra_pulsesrc = gst::ElementFactory::make("pulsesrc").build()?;
+            extra_pulsesrc.set_property("device", extra_source.name.as_str());
+            let extra_audioconvert = gst::ElementFactory::make("audioconvert").build()?;
+            let extra_audioresample = gst::ElementFactory::make("audioresample").build()?;
+
+            pipeline.add_many(&[&extra_pulsesrc, &extra_audioconvert, &extra_audioresample])?;
+            Element::link_many(&[&extra_pulsesrc, &extra_audioconvert, &extra_audioresample])?;
+
+            let mixer_pad = audiomixer
+                .request_pad_simple("sink_%u")
+                .ok_or_else(|| glib::bool_error!("Failed to request audiomixer pad"))?;
+            //Mixer pads accept gains from 0 to 10
+            mixer_pad.set_property("volume", extra_source.gain.clamp(0.0, 10.0));
+            extra_audioresample
+                .static_pad("src")
+                .ok_or_else(|| glib::bool_error!("audioresample has no src pad"))?
+                .link(&mixer_pad)?;
+
+            debug!(
+                "Mixing extra audio source {} (gain {})",
+                extra_source.name, extra_source.gain
+            );
+        }
+
+        let mut stats = StatsCollector::new(discord_streamer.clone().upcast());
+        stats.watch_frame_queue(&video_encoder_queue);
+        stats.watch_video_rate(&videorate);
+        stats.watch_video_encoder(&encoder)?;
+        for layer_encoder in &layer_encoders {
+            stats.watch_layer_encoder(layer_encoder)?;
+        }
+        stats.watch_audio_encoder(&opusenc)?;
+        stats.watch_audio_source(&pulsesrc)?;
+        for queue in [
+            &video_encoder_queue,
+            &video_webrtc_queue,
+            &audio_encoder_queue,
+            &audio_webrtc_queue,
+        ] {
+            stats.watch_queue(queue);
+        }
+
+        write_graph(&pipeline, graph_dir.as_deref(), "created");
 
         Ok(GstHandle {
             pipeline,
             encoder,
             encoder_type: encoder_to_use,
+            volume,
+            video_selector,
+            live_video_pad,
+            placeholder_video_pad,
+            paused_video_pad,
+            audio_selector,
+            live_audio_pad,
+            silent_audio_pad,
+            placeholder_shown: AtomicBool::new(false),
+            paused: AtomicBool::new(false),
+            text_overlay,
+            click_overlay,
+            privacy_blur,
+            graph_dir,
+            stats,
+            layers: stream_layers,
         })
     }
 
-    pub async fn start(
-        &self,
-    ) -> Result<StateChangeSuccess, StateChangeError> {
+    /// Gets the video layers to announce, fewer than requested if the streamer can't simulcast
+    pub fn layers(&self) -> &[StreamLayer] {
+        &self.layers
+    }
+
+    /// Gets the stream's statistics, rates are averaged since the last call
+    pub fn stats(&self) -> StreamStats {
+        self.stats.collect()
+    }
+
+    /// Gets the pipeline graph in Graphviz format
+    pub fn graph(&self) -> String {
+        debug_bin_to_dot_data(&self.pipeline, DebugGraphDetails::ALL).to_string()
+    }
+
+    /// Writes the pipeline graph into the graph folder, if enabled
+    pub fn dump_graph(&self, name: &str) {
+        write_graph(&self.pipeline, self.graph_dir.as_deref(), name);
+    }
+
+    /// Blurs the smallest area containing all regions, in capture coordinates, or nothing if empty
+    pub fn blur_regions(&self, regions: &[Rect]) {
+        let blur = match &self.privacy_blur {
+            Some(blur) => blur,
+            None => return,
+        };
+
+        let area = match regions.iter().copied().reduce(|a, b| a.union(&b)) {
+            Some(area) => area,
+            None => {
+                blur.pad.set_property("alpha", 0f64);
+                blur.pending.store(false, Ordering::SeqCst);
+                return;
+            }
+        };
+
+        //Cropping is relative to the frame edges, so the frame size is needed
+        let frame_size = blur
+            .crop
+            .static_pad("sink")
+            .and_then(|pad| pad.current_caps())
+            .and_then(|caps| {
+                let s = caps.structure(0)?;
+                Some((s.get::<i32>("width").ok()?, s.get::<i32>("height").ok()?))
+            });
+        let (frame_width, frame_height) = match frame_size {
+            Some(size) => size,
+            None => {
+                //Nothing has been captured yet, blur everything until the frame size is known
+                debug!("No video frame size yet, blurring the whole frame");
+                for side in ["left", "top", "right", "bottom"] {
+                    blur.crop.set_property(side, 0i32);
+                }
+                blur.pad.set_property("xpos", 0i32);
+                blur.pad.set_property("ypos", 0i32);
+                blur.pad.set_property("alpha", 1f64);
+                blur.pending.store(true, Ordering::SeqCst);
+                return;
+            }
+        };
+
+        let (x, y) = (area.x as i32, area.y as i32);
+        blur.crop.set_property("left", x);
+        blur.crop.set_property("top", y);
+        blur.crop
+            .set_property("right", (frame_width - x - area.width as i32).max(0));
+        blur.crop
+            .set_property("bottom", (frame_height - y - area.height as i32).max(0));
+        blur.pad.set_property("xpos", x);
+        blur.pad.set_property("ypos", y);
+        blur.pad.set_property("alpha", 1f64);
+        blur.pending.store(false, Ordering::SeqCst);
+        debug!("Privacy blur covering {:?}", area);
+    }
+
+    /// Whether the privacy blur is covering the whole frame and should be positioned again
+    pub fn privacy_blur_pending(&self) -> bool {
+        self.privacy_blur
+            .as_ref()
+            .map_or(false, |blur| blur.pending.load(Ordering::SeqCst))
+    }
+
+    /// Draws a click highlight at a position in the capture, or clears it
+    pub fn highlight_click(&self, position: Option<(i32, i32)>) {
+        let overlay = match &self.click_overlay {
+            Some(overlay) => overlay,
+            None => return,
+        };
+
+        match position {
+            Some((x, y)) => {
+                overlay.set_property("x", x - CLICK_RING_SIZE / 2);
+                overlay.set_property("y", y - CLICK_RING_SIZE / 2);
+                overlay.set_property("data", CLICK_RING_SVG);
+            }
+            None => overlay.set_property("data", EMPTY_SVG),
+        }
+    }
+
+    /// Switches the outgoing video between the capture and the placeholder
+    pub fn show_placeholder(&self, placeholder: bool) {
+        self.placeholder_shown.store(placeholder, Ordering::SeqCst);
+        self.update_video_input();
+        debug!(
+            "Video placeholder {}",
+            if placeholder { "shown" } else { "hidden" }
+        );
+    }
+
+    /// Changes the overlay text, `None` hides it
+    pub fn set_overlay_text(&self, text: Option<&str>) {
+        match text {
+            Some(text) => {
+                self.text_overlay.set_property("text", text);
+                self.text_overlay.set_property("silent", false);
+            }
+            None => self.text_overlay.set_property("silent", true),
+        }
+    }
+
+    /// Replaces video with a paused image and audio with silence, the session stays connected
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::SeqCst);
+        self.update_video_input();
+
+        let audio_pad = if paused {
+            &self.silent_audio_pad
+        } else {
+            &self.live_audio_pad
+        };
+        self.audio_selector.set_property("active-pad", audio_pad);
+        debug!("Stream {}", if paused { "paused" } else { "resumed" });
+    }
+
+    /// Pausing takes priority over the placeholder, which takes priority over the capture
+    fn update_video_input(&self) {
+        let pad = if self.paused.load(Ordering::SeqCst) {
+            &self.paused_video_pad
+        } else if self.placeholder_shown.load(Ordering::SeqCst) {
+            &self.placeholder_video_pad
+        } else {
+            &self.live_video_pad
+        };
+        self.video_selector.set_property("active-pad", pad);
+    }
+
+    /// Drains all pending messages on the pipeline bus without blocking
+    pub fn poll_events(&self) -> Vec<PipelineEvent> {
+        let bus = match self.pipeline.bus() {
+            Some(bus) => bus,
+            None => return vec![],
+        };
+
+        let mut events = vec![];
+        for msg in bus.iter() {
+            match msg.view() {
+                gst::MessageView::Element(e) => {
+                    let s = match e.structure() {
+                        Some(s) => s,
+                        None => continue,
+                    };
+                    if s.has_name("level") {
+                        events.push(PipelineEvent::AudioLevels(AudioLevels {
+                            rms: level_values(s, "rms"),
+                            peak: level_values(s, "peak"),
+                        }));
+                    }
+                }
+                gst::MessageView::StateChanged(change)
+                    if msg.src() == Some(self.pipeline.upcast_ref::<gst::Object>()) =>
+                {
+                    debug!(
+                        "Pipeline state changed from {:?} to {:?}",
+                        change.old(),
+                        change.current()
+                    );
+                    self.dump_graph(
+                        &format!("{:?}-{:?}", change.old(), change.current()).to_lowercase(),
+                    );
+                }
+                gst::MessageView::Error(err) => {
+                    error!(
+                        "Pipeline error from {}: {} ({:?})",
+                        err.src()
+                            .map(|src| src.path_string().to_string())
+                            .unwrap_or_default(),
+                        err.error(),
+                        err.debug()
+                    );
+                    self.dump_graph("error");
+                    events.push(PipelineEvent::Error(err.error().to_string()));
+                }
+                gst::MessageView::Eos(_) => {
+                    warn!("Pipeline reached end of stream");
+                    events.push(PipelineEvent::Eos);
+                }
+                _ => {}
+            }
+        }
+
+        events
+    }
+
+    /// Applies a captured application's mix settings to the stream audio, ducking it if the user is speaking
+    pub fn set_audio_mix(&self, mix: AudioMix, ducking: DuckingSettings, speaking: bool) {
+        let mut gain = mix.gain;
+        if ducking.enabled && speaking {
+            gain *= ducking.level;
+        }
+
+        //The volume element accepts gains from 0 to 10
+        self.volume.set_property("volume", gain.clamp(0.0, 10.0));
+        self.volume.set_property("mute", mix.mute);
+        trace!("Stream audio gain set to {} (muted: {})", gain, mix.mute);
+    }
+
+    pub async fn start(&self) -> Result<StateChangeSuccess, StateChangeError> {
         self.pipeline.set_state(gst::State::Playing)?;
 
         Ok(StateChangeSuccess::Success)
     }
 }
+
+/// Reads a per-channel dB array out of a `level` element message
+fn level_values(s: &gst::StructureRef, field: &str) -> Vec<f64> {
+    match s.get::<glib::ValueArray>(field) {
+        Ok(values) => values.iter().filter_map(|v| v.get::<f64>().ok()).collect(),
+        Err(e) => {
+            error!("Failed to read {} from level message: {:?}", field, e);
+            vec![]
+        }
+    }
+}
+
+/// Writes a pipeline graph named after the time and an event into a folder, if one is set
+fn write_graph(pipeline: &gst::Pipeline, dir: Option<&Path>, name: &str) {
+    let dir = match dir {
+        Some(dir) => dir,
+        None => return,
+    };
+
+    let path = dir.join(format!(
+        "gst-{}-{}.dot",
+        chrono::Local::now().format("%H-%M-%S%.3f"),
+        name
+    ));
+    let graph = debug_bin_to_dot_data(pipeline, DebugGraphDetails::ALL);
+    match std::fs::write(&path, graph.as_str()) {
+        Ok(_) => debug!("Pipeline graph written to {}", path.display()),
+        Err(e) => error!(
+            "Failed to write pipeline graph to {}: {}",
+            path.display(),
+            e
+        ),
+    }
+}
+
+/// Renders a pipeline graph to SVG with Graphviz, if it's installed
+pub async fn render_svg(graph: &str) -> Option<String> {
+    let mut child = match tokio::process::Command::new("dot")
+        .arg("-Tsvg")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(e) => {
+            warn!("Failed to run dot, is Graphviz installed? {}", e);
+            return None;
+        }
+    };
+
+    //Written separately so a full stdout pipe can't block the write
+    let mut stdin = child.stdin.take()?;
+    let graph = graph.to_string();
+    let writer = tokio::spawn(async move { stdin.write_all(graph.as_bytes()).await });
+
+    let output = match child.wait_with_output().await {
+        Ok(output) => output,
+        Err(e) => {
+            warn!("Failed to render pipeline graph: {}", e);
+            return None;
+        }
+    };
+    let _ = writer.await;
+
+    if !output.status.success() {
+        warn!("dot failed to render pipeline graph: {}", output.status);
+        return None;
+    }
+
+    String::from_utf8(output.stdout).ok()
+}
+
+/// Creates a video encoder tuned for the content
+fn make_video_encoder(
+    encoder_to_use: VideoEncoderType,
+    tuning: &VideoTuning,
+    keyframe_interval: Option<u32>,
+    bitrate: u32,
+) -> Result<Element, glib::BoolError> {
+    //Chose encoder based on constructor params
+    let encoder = match encoder_to_use {
+        VideoEncoderType::H264(settings) => {
+            //Use nvidia encoder based on settings
+            if settings.nvidia_encoder {
+                let nvh264enc = gst::ElementFactory::make("nvh264enc").build()?;
+                nvh264enc.set_property(
+                    "gop-size",
+                    keyframe_interval.unwrap_or(DEFAULT_KEYFRAME_INTERVAL) as i32,
+                );
+                if let Some(preset) = tuning.nvenc_preset {
+                    nvh264enc.set_property_from_str("preset", preset);
+                }
+                nvh264enc.set_property("bitrate", bitrate / 1000);
+                nvh264enc.set_property_from_str("rc-mode", "cbr-ld-hq");
+                nvh264enc.set_property("zerolatency", true);
+                nvh264enc
+            } else {
+                let x264enc = gst::ElementFactory::make("x264enc").build()?;
+                x264enc.set_property("threads", 12u32);
+                x264enc.set_property_from_str("tune", tuning.x264_tune);
+                x264enc.set_property_from_str("speed-preset", tuning.x264_preset);
+                x264enc.set_property(
+                    "key-int-max",
+                    keyframe_interval.unwrap_or(DEFAULT_KEYFRAME_INTERVAL),
+                );
+                x264enc.set_property("bitrate", bitrate / 1000);
+                x264enc.set_property("b-adapt", false);
+                x264enc.set_property("vbv-buf-capacity", 120u32);
+                x264enc
+            }
+        }
+        VideoEncoderType::VP8 => {
+            let vp8enc = gst::ElementFactory::make("vp8enc").build()?;
+            vp8enc.set_property("threads", 12i32);
+            vp8enc.set_property("cpu-used", tuning.vpx_cpu_used);
+            if let Some(keyframe_interval) = keyframe_interval {
+                vp8enc.set_property("keyframe-max-dist", keyframe_interval as i32);
+            }
+            vp8enc.set_property("target-bitrate", bitrate.min(i32::MAX as u32) as i32);
+            vp8enc.set_property_from_str("end-usage", "cbr");
+            vp8enc.set_property("buffer-initial-size", 100i32);
+            vp8enc.set_property("buffer-optimal-size", 120i32);
+            vp8enc.set_property("buffer-size", 150i32);
+            vp8enc.set_property("max-intra-bitrate", 250i32);
+            vp8enc.set_property_from_str("error-resilient", "default");
+            vp8enc.set_property("lag-in-frames", 0i32);
+            vp8enc
+        }
+        VideoEncoderType::VP9 => {
+            let vp9enc = gst::ElementFactory::make("vp9enc").build()?;
+            vp9enc.set_property("threads", 12i32);
+            vp9enc.set_property("cpu-used", tuning.vpx_cpu_used);
+            if let Some(keyframe_interval) = keyframe_interval {
+                vp9enc.set_property("keyframe-max-dist", keyframe_interval as i32);
+            }
+            vp9enc.set_property("target-bitrate", bitrate.min(i32::MAX as u32) as i32);
+            vp9enc.set_property_from_str("end-usage", "cbr");
+            vp9enc.set_property("buffer-initial-size", 100i32);
+            vp9enc.set_property("buffer-optimal-size", 120i32);
+            vp9enc.set_property("buffer-size", 150i32);
+            vp9enc.set_property("max-intra-bitrate", 250i32);
+            vp9enc.set_property_from_str("error-resilient", "default");
+            vp9enc.set_property("lag-in-frames", 0i32);
+            vp9enc
+        }
+    };
+
+    Ok(encoder)
+}
+
+/// Creates a queue for raw frames that drops the oldest when the encoder falls behind
+fn make_frame_queue() -> Result<Element, glib::BoolError> {
+    let queue = gst::ElementFactory::make("queue").build()?;
+    queue.set_property_from_str("leaky", "downstream");
+    queue.set_property("max-size-buffers", MAX_QUEUED_FRAMES);
+    queue.set_property("max-size-bytes", 0u32);
+    queue.set_property("max-size-time", 0u64);
+    Ok(queue)
+}
+
+/// Creates a bounded queue for encoded data, a full queue holds back the encoder instead of dropping
+fn make_encoded_queue() -> Result<Element, glib::BoolError> {
+    let queue = gst::ElementFactory::make("queue").build()?;
+    queue.set_property("max-size-buffers", 0u32);
+    queue.set_property("max-size-bytes", 0u32);
+    queue.set_property(
+        "max-size-time",
+        gst::ClockTime::from_mseconds(MAX_QUEUED_ENCODED_MS).nseconds(),
+    );
+    Ok(queue)
+}
+
+/// Aligns a `textoverlay` or `clockoverlay` to a position
+fn set_text_position(overlay: &Element, position: OverlayPosition) {
+    let (valignment, halignment) = match position {
+        OverlayPosition::TopLeft => ("top", "left"),
+        OverlayPosition::TopRight => ("top", "right"),
+        OverlayPosition::BottomLeft => ("bottom", "left"),
+        OverlayPosition::BottomRight => ("bottom", "right"),
+        OverlayPosition::Center => ("center", "center"),
+    };
+    overlay.set_property_from_str("valignment", valignment);
+    overlay.set_property_from_str("halignment", halignment);
+}
+
+/// Places a `gdkpixbufoverlay`, negative offsets are measured from the right and bottom edges
+fn set_image_position(overlay: &Element, position: OverlayPosition, width: u32, height: u32) {
+    let (offset_x, offset_y) = match position {
+        OverlayPosition::TopLeft => (OVERLAY_MARGIN, OVERLAY_MARGIN),
+        OverlayPosition::TopRight => (-OVERLAY_MARGIN, OVERLAY_MARGIN),
+        OverlayPosition::BottomLeft => (OVERLAY_MARGIN, -OVERLAY_MARGIN),
+        OverlayPosition::BottomRight => (-OVERLAY_MARGIN, -OVERLAY_MARGIN),
+        OverlayPosition::Center => {
+            overlay.set_property("relative-x", 0.5f64);
+            overlay.set_property("relative-y", 0.5f64);
+            (-(width as i32) / 2, -(height as i32) / 2)
+        }
+    };
+    overlay.set_property("offset-x", offset_x);
+    overlay.set_property("offset-y", offset_y);
+}
+
+/// Converts and scales a filler source for the video selector, returning the capsfilter that pins its caps
+fn add_filler_conversion(
+    pipeline: &gst::Pipeline,
+    source: &Element,
+) -> Result<Element, GstInitializationError> {
+    let convert = gst::ElementFactory::make("videoconvert").build()?;
+    let scale = gst::ElementFactory::make("videoscale").build()?;
+    let capsfilter = gst::ElementFactory::make("capsfilter").build()?;
+
+    pipeline.add_many(&[&convert, &scale, &capsfilter])?;
+    Element::link_many(&[source, &convert, &scale, &capsfilter])?;
+
+    Ok(capsfilter)
+}
+
+/// Caps for filler sources matching the live capture, the framerate is left to them since videorate evens it out
+fn filler_caps(live: &gst::CapsRef) -> Option<gst::Caps> {
+    let s = live.structure(0)?;
+    let mut caps = gst::Caps::builder("video/x-raw")
+        .field("format", s.get::<&str>("format").ok()?)
+        .field("width", s.get::<i32>("width").ok()?)
+        .field("height", s.get::<i32>("height").ok()?);
+    if let Ok(par) = s.get::<gst::Fraction>("pixel-aspect-ratio") {
+        caps = caps.field("pixel-aspect-ratio", par);
+    }
+
+    Some(caps.build())
+}
+
+/// Describes a video layer for the client, layer 0 is the full-size video
+fn stream_layer(
+    layer: u8,
+    ssrcs: StreamSSRCs,
+    bitrate: u32,
+    fps: i32,
+    resolution: &StreamResolutionInformation,
+) -> StreamLayer {
+    let quality = 100 >> layer;
+    let layer_ssrcs = ssrcs.layer(layer);
+    //Same sizes as the layer capsfilters, fixed only if the full size is
+    let (width, height) = match resolution.is_fixed {
+        true => (
+            ((resolution.width >> layer) & !1).max(2),
+            ((resolution.height >> layer) & !1).max(2),
+        ),
+        false => (0, 0),
+    };
+
+    StreamLayer {
+        rid: quality.to_string(),
+        quality,
+        ssrc: layer_ssrcs.video,
+        rtx_ssrc: layer_ssrcs.rtx,
+        max_bitrate: bitrate,
+        max_framerate: fps,
+        width,
+        height,
+        is_fixed: resolution.is_fixed,
+    }
+}
+
+/// Links an element's src pad to a new `sink_%u` input of a selector or mixer, returning that input
+fn link_to_new_input(src: &Element, target: &Element) -> Result<gst::Pad, GstInitializationError> {
+    let sink_pad = target
+        .request_pad_simple("sink_%u")
+        .ok_or_else(|| glib::bool_error!("Failed to request {} pad", target.name()))?;
+    src.static_pad("src")
+        .ok_or_else(|| glib::bool_error!("Element has no src pad"))?
+        .link(&sink_pad)?;
+
+    Ok(sink_pad)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profiles_resolve_to_their_defaults() {
+        let options = OpusOptions::default();
+        for profile in [
+            AudioProfile::Voice,
+            AudioProfile::Balanced,
+            AudioProfile::Music,
+        ] {
+            assert_eq!(
+                OpusSettings::resolve(profile, &options).unwrap(),
+                OpusSettings::from(profile)
+            );
+        }
+
+        let voice = OpusSettings::from(AudioProfile::Voice);
+        assert_eq!(voice.bitrate, 64000);
+        assert_eq!(voice.audio_type, OpusAudioType::Voice);
+        assert!(voice.dtx);
+        assert_eq!(OpusSettings::from(AudioProfile::Balanced).bitrate, 128000);
+        assert_eq!(OpusSettings::from(AudioProfile::Music).bitrate, 256000);
+    }
+
+    #[test]
+    fn overrides_replace_profile_values() {
+        let options = OpusOptions {
+            bitrate: Some(96000),
+            frame_size: Some(40.0),
+            dtx: Some(true),
+            ..Default::default()
+        };
+        let settings = OpusSettings::resolve(AudioProfile::Music, &options).unwrap();
+        assert_eq!(settings.bitrate, 96000);
+        assert_eq!(settings.frame_size, 40.0);
+        assert!(settings.dtx);
+        assert_eq!(settings.complexity, 10);
+    }
+
+    #[test]
+    fn content_hints_pick_their_tuning() {
+        assert_eq!(
+            VideoTuning::from(ContentHint::Motion),
+            VideoTuning {
+                x264_preset: "ultrafast",
+                x264_tune: "zerolatency",
+                nvenc_preset: None,
+                vpx_cpu_used: -16,
+                keyframe_interval: None,
+                scale_method: "bilinear",
+                max_fps: i32::MAX,
+            }
+        );
+        assert_eq!(
+            VideoTuning::from(ContentHint::Detail),
+            VideoTuning {
+                x264_preset: "veryfast",
+                x264_tune: "zerolatency",
+                nvenc_preset: Some("low-latency-hq"),
+                vpx_cpu_used: -8,
+                keyframe_interval: Some(15),
+                scale_method: "lanczos",
+                max_fps: 30,
+            }
+        );
+        assert_eq!(
+            VideoTuning::from(ContentHint::Text),
+            VideoTuning {
+                x264_preset: "faster",
+                x264_tune: "zerolatency+stillimage",
+                nvenc_preset: Some("low-latency-hq"),
+                vpx_cpu_used: -5,
+                keyframe_interval: Some(30),
+                scale_method: "lanczos",
+                max_fps: 15,
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_settings_outside_opus_limits() {
+        let resolve =
+            |options: OpusOptions| OpusSettings::resolve(AudioProfile::Balanced, &options);
+
+        for bitrate in [6000, 510000] {
+            assert!(resolve(OpusOptions {
+                bitrate: Some(bitrate),
+                ..Default::default()
+            })
+            .is_ok());
+        }
+        for bitrate in [5999, 510001] {
+            assert!(matches!(
+                resolve(OpusOptions { bitrate: Some(bitrate), ..Default::default() }),
+                Err(InvalidOpusSettings::Bitrate(b)) if b == bitrate
+            ));
+        }
+        for frame_size in [2.5, 5.0, 30.0, 120.0] {
+            assert!(matches!(
+                resolve(OpusOptions {
+                    frame_size: Some(frame_size),
+                    ..Default::default()
+                }),
+                Err(InvalidOpusSettings::FrameSize(_))
+            ));
+        }
+        for percentage in [-1, 101] {
+            assert!(matches!(
+                resolve(OpusOptions {
+                    packet_loss_percentage: Some(percentage),
+                    ..Default::default()
+                }),
+                Err(InvalidOpusSettings::PacketLossPercentage(_))
+            ));
+        }
+        for complexity in [-1, 11] {
+            assert!(matches!(
+                resolve(OpusOptions {
+                    complexity: Some(complexity),
+                    ..Default::default()
+                }),
+                Err(InvalidOpusSettings::Complexity(_))
+            ));
+        }
+    }
+}
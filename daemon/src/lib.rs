@@ -1,4 +1,7 @@
 use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    path::PathBuf,
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
@@ -7,16 +10,24 @@ use std::{
 };
 
 use sysinfo::{Pid, PidExt, Process, ProcessExt, SystemExt};
-use tracing::{error, info};
+use tracing::{debug, error, info, warn};
 
-use pulse::PulseHandle;
-use socket::{Application, SocketListenerCommand, WebSocket};
-use x::XServerHandle;
+use metrics::METRICS;
+use pulse::{AudioApplication, PulseHandle};
+use socket::{
+    Application, PreviewOptions, PrivacyMode, PrivacyOptions, SocketListenerCommand,
+    StreamStopReason, WebSocket,
+};
+use ssrc::StreamSSRCs;
+use x::{CaptureSource, WindowEvent, XServerHandle};
 // Makes sure typing is preserved
 use u32 as pid;
 use u32 as xid;
 
-use crate::gstreamer::{GstHandle, H264Settings, VideoEncoderType};
+use crate::gstreamer::{
+    AudioMix, CursorSettings, DuckingSettings, GstHandle, H264Settings, OpusSettings,
+    PipelineEvent, VideoEncoderType, VideoTuning, render_svg,
+};
 
 use tokio::{
     sync::{
@@ -27,10 +38,16 @@ use tokio::{
 };
 
 mod gstreamer;
+pub mod metrics;
 mod pulse;
 pub mod socket;
+mod ssrc;
+mod stats;
 mod x;
 
+/// Shortest time between stream previews, each one is a full screenshot
+const MIN_PREVIEW_INTERVAL_SECS: u64 = 5;
+
 pub struct CommandProcessor {
     thread: Option<tokio::task::JoinHandle<()>>,
 }
@@ -41,9 +58,10 @@ impl CommandProcessor {
         run: Arc<AtomicBool>,
         sleep_time: Duration,
         websocket: Arc<Mutex<WebSocket>>,
+        graph_dir: Option<PathBuf>,
     ) -> Self {
         let thread = tokio::spawn(async move {
-            let mut pulse = match PulseHandle::new() {
+            let pulse = match PulseHandle::new().await {
                 Ok(handle) => handle,
                 Err(e) => {
                     error!("Pulse error: {}", e);
@@ -62,17 +80,51 @@ impl CommandProcessor {
             };
 
             let mut last_stream_preview: Option<time::Instant> = None;
-            let mut current_xid = None;
+            let mut current_source: Option<CaptureSource> = None;
+            let mut current_pid = None;
+            let mut preview_options = PreviewOptions::default();
+            let mut preview_interval = Duration::from_secs(10 * 60);
+            // Hash of the last sent stream preview, used to skip identical frames
+            let mut last_preview_hash: Option<u64> = None;
+            let mut stats_interval = Duration::from_secs(5);
+            let mut last_stream_stats: Option<time::Instant> = None;
+
+            // Stream-only audio settings, kept across streams
+            let mut audio_mixes: HashMap<pid, AudioMix> = HashMap::new();
+            let mut ducking = DuckingSettings::default();
+            let mut user_speaking = false;
+
+            // When the current click highlight should be cleared
+            let mut click_highlight_until: Option<time::Instant> = None;
+            // Whether the captured window is currently unmapped (minimized, hidden)
+            let mut window_hidden = false;
+            // Whether the stream is sending the paused image instead of the capture
+            let mut stream_paused = false;
+            let mut privacy = PrivacyOptions::default();
+            // Whether a protected window currently covers part of the capture
+            let mut privacy_active = false;
+            // Commands raised by the processor itself, handled before socket commands
+            let mut internal_command: Option<SocketListenerCommand> = None;
 
             let mut gst_is_loaded = false;
 
             let mut stream = None;
 
+            // Command being handled, recorded once its handler finishes or bails out early
+            let mut current_command: Option<&'static str> = None;
+            // Set by every error site of the current command's handler
+            let mut command_failed = false;
+
             loop {
+                if let Some(name) = current_command.take() {
+                    METRICS.record_command(name, !command_failed);
+                }
+
                 if !run.load(Ordering::SeqCst) {
                     // Kill websocket if still running
                     stream.take();
-                    current_xid.take();
+                    current_source.take();
+                    current_pid.take();
                     if gst_is_loaded {
                         unsafe {
                             gst::deinit();
@@ -82,8 +134,16 @@ impl CommandProcessor {
                     break;
                 }
 
-                match receiver.try_recv() {
+                let next_command = match internal_command.take() {
+                    Some(cmd) => Ok(cmd),
+                    None => receiver.try_recv(),
+                };
+
+                match next_command {
                     Ok(cmd) => {
+                        let _ = current_command.insert(cmd.name());
+                        command_failed = false;
+
                         match cmd {
                             SocketListenerCommand::StartStream {
                                 pid,
@@ -94,27 +154,102 @@ impl CommandProcessor {
                                 secret_key,
                                 voice_ssrc,
                                 base_ssrc,
+                                audio_ssrc,
                                 ip,
                                 port,
+                                passthrough_sink,
+                                audio_routing,
+                                extra_source,
+                                audio_profile,
+                                opus,
+                                capture,
+                                preview,
+                                preview_interval: preview_secs,
+                                show_cursor,
+                                highlight_clicks,
+                                privacy: privacy_options,
+                                overlay,
+                                stats_interval: stats_secs,
+                                content_hint,
+                                simulcast_layers,
+                                video_bitrate,
                             } => {
                                 info!("[StartStream] Command received");
-                                match pulse.setup_audio_capture(None) {
+
+                                if framerate == 0 {
+                                    command_failed = true;
+                                    error!("Invalid framerate: 0");
+                                    continue;
+                                }
+
+                                let ssrcs = match StreamSSRCs::allocate(
+                                    base_ssrc,
+                                    audio_ssrc,
+                                    voice_ssrc,
+                                    simulcast_layers,
+                                ) {
+                                    Ok(ssrcs) => ssrcs,
+                                    Err(e) => {
+                                        command_failed = true;
+                                        error!("Invalid stream SSRCs: {}", e);
+                                        continue;
+                                    }
+                                };
+
+                                let opus = match OpusSettings::resolve(audio_profile, &opus) {
+                                    Ok(opus) => opus,
+                                    Err(e) => {
+                                        command_failed = true;
+                                        error!("Invalid audio encoder settings: {}", e);
+                                        continue;
+                                    }
+                                };
+
+                                let capture_source = match x.resolve_capture_target(xid, &capture)
+                                {
+                                    Ok(source) => source,
+                                    Err(e) => {
+                                        if let x::CaptureTargetError::X(_) = e {
+                                            METRICS.record_x_error();
+                                        }
+                                        command_failed = true;
+                                        error!("Invalid capture target: {}", e);
+                                        continue;
+                                    }
+                                };
+
+                                match pulse
+                                    .setup_audio_capture(passthrough_sink.as_deref(), audio_routing)
+                                    .await
+                                {
                                     Ok(_) => {}
                                     Err(e) => {
+                                        command_failed = true;
                                         error!("Failed to setup pulse capture: {}", e);
                                         continue;
                                     }
                                 }
 
-                                match pulse.start_capture(pid) {
+                                match pulse.start_capture(pid).await {
                                     Ok(_) => {}
                                     Err(e) => {
+                                        command_failed = true;
                                         error!("Failed to start pulse capture: {}", e);
                                         continue;
                                     }
                                 }
 
-                                let _ = current_xid.insert(xid);
+                                let _ = current_source.insert(capture_source);
+                                let _ = current_pid.insert(pid);
+                                window_hidden = false;
+                                stream_paused = false;
+                                if let CaptureSource::Window(xid) = capture_source {
+                                    if let Err(e) = x.watch_window(xid) {
+                                        METRICS.record_x_error();
+                                        command_failed = true;
+                                        error!("Failed to watch window {} for changes: {}", xid, e);
+                                    }
+                                }
 
                                 // Quick and drity check to try to detect Nvidia drivers
                                 // TODO: Find a better way to do this
@@ -127,44 +262,154 @@ impl CommandProcessor {
                                     gst::init().expect("Failed to intialize gstreamer");
                                 }
 
-                                let gst = GstHandle::new(
+                                let gst = match GstHandle::new(
                                     VideoEncoderType::H264(H264Settings {
                                         nvidia_encoder: false,
                                     }),
-                                    xid,
+                                    capture_source,
                                     resolution.clone(),
                                     framerate.into(),
+                                    VideoTuning::from(content_hint),
+                                    video_bitrate,
                                     secret_key,
-                                    base_ssrc,
+                                    ssrcs,
                                     format!("{}:{}", ip, port),
+                                    extra_source,
+                                    opus,
+                                    CursorSettings {
+                                        show: show_cursor,
+                                        highlight_clicks,
+                                    },
+                                    privacy_options.mode,
+                                    overlay,
+                                    graph_dir.clone(),
                                 )
                                 .await
-                                .expect("Failed to initialize gstreamer pipeline");
-                                gst.start()
+                                {
+                                    Ok(gst) => gst,
+                                    Err(e) => {
+                                        command_failed = true;
+                                        error!("Failed to create stream pipeline: {}", e);
+                                        current_pid.take();
+                                        if let Some(CaptureSource::Window(xid)) =
+                                            current_source.take()
+                                        {
+                                            let _ = x.unwatch_window(xid);
+                                        }
+                                        pulse.stop_capture().await;
+                                        pulse.teardown_audio_capture().await;
+                                        continue;
+                                    }
+                                };
+                                gst.set_audio_mix(
+                                    audio_mixes.get(&pid).copied().unwrap_or_default(),
+                                    ducking,
+                                    user_speaking,
+                                );
+
+                                // Shield before the first frame goes out
+                                privacy = privacy_options;
+                                if !privacy.patterns.is_empty() {
+                                    if let Err(e) = x.watch_stacking(true) {
+                                        METRICS.record_x_error();
+                                        command_failed = true;
+                                        error!("Failed to watch window stacking: {}", e);
+                                    }
+                                }
+                                privacy_active = update_privacy_shield(
+                                    &x,
+                                    &gst,
+                                    capture_source,
+                                    &privacy,
+                                    window_hidden,
+                                );
+
+                                if let Err(e) = gst.start().await {
+                                    command_failed = true;
+                                    error!("Failed to start stream: {}", e);
+                                    // Tear down like any other failed pipeline, telling the client why
+                                    let _ = stream.insert(gst);
+                                    let _ = internal_command.insert(
+                                        SocketListenerCommand::StopStreamInternal {
+                                            reason: StreamStopReason::PipelineError,
+                                        },
+                                    );
+                                    continue;
+                                }
+
+                                // The client announces the layers to Discord, only the ones actually built
+                                if let Err(e) = websocket
+                                    .lock()
+                                    .await
+                                    .stream_layers(&rtc_connection_id, ssrcs.audio, gst.layers())
                                     .await
-                                    .expect("Failed to start stream");
+                                {
+                                    command_failed = true;
+                                    error!("Failed to send stream layers: {}", e);
+                                }
 
                                 let _ = stream.insert(gst);
+                                METRICS.stream_started();
+                                preview_options = preview;
+                                preview_interval = preview_interval_from_secs(preview_secs);
+                                last_stream_preview.take();
+                                last_preview_hash.take();
+                                stats_interval = Duration::from_secs(stats_secs);
+                                let _ = last_stream_stats.insert(time::Instant::now());
+                                click_highlight_until.take();
+                                if highlight_clicks {
+                                    match x.watch_clicks(true) {
+                                        Ok(true) => {}
+                                        Ok(false) => {
+                                            command_failed = true;
+                                            error!("X server can't report clicks, highlights disabled")
+                                        }
+                                        Err(e) => {
+                                            METRICS.record_x_error();
+                                            command_failed = true;
+                                            error!("Failed to watch clicks: {}", e)
+                                        }
+                                    }
+                                }
 
                                 info!("[StartStream] Command processed (stream started)");
                             }
                             SocketListenerCommand::StopStream
-                            | SocketListenerCommand::StopStreamInternal => {
+                            | SocketListenerCommand::StopStreamInternal { .. } => {
                                 info!("[StopStream] Command received");
 
                                 // Kill gstreamer
                                 stream.take();
+                                METRICS.stream_stopped();
+                                current_pid.take();
+                                if let Some(CaptureSource::Window(xid)) = current_source.take() {
+                                    // Fails if the window was destroyed, which is fine
+                                    let _ = x.unwatch_window(xid);
+                                }
+                                if let Err(e) = x.watch_clicks(false) {
+                                    METRICS.record_x_error();
+                                    command_failed = true;
+                                    error!("Failed to stop watching clicks: {}", e);
+                                }
+                                if let Err(e) = x.watch_stacking(false) {
+                                    METRICS.record_x_error();
+                                    command_failed = true;
+                                    error!("Failed to stop watching window stacking: {}", e);
+                                }
+                                privacy_active = false;
 
-                                pulse.stop_capture();
-                                pulse.teardown_audio_capture();
+                                pulse.stop_capture().await;
+                                pulse.teardown_audio_capture().await;
 
                                 info!("[StopStream] Command processed (stream stopped)");
 
                                 // If stream was stopped internally, send a notification to the client
-                                if cmd == SocketListenerCommand::StopStreamInternal {
+                                if let SocketListenerCommand::StopStreamInternal { reason } = cmd {
+                                    info!("Stream stopped internally: {:?}", reason);
                                     if let Err(e) =
-                                        websocket.lock().await.stream_stop_internal().await
+                                        websocket.lock().await.stream_stop_internal(reason).await
                                     {
+                                        command_failed = true;
                                         error!(
                                             "Failed to notify client of internal stream stop: {:?}",
                                             e
@@ -172,15 +417,38 @@ impl CommandProcessor {
                                     }
                                 }
                             }
+                            SocketListenerCommand::PauseStream
+                            | SocketListenerCommand::ResumeStream => {
+                                let paused = cmd == SocketListenerCommand::PauseStream;
+                                let name = if paused { "PauseStream" } else { "ResumeStream" };
+                                info!("[{}] Command received", name);
+
+                                let gst = match &stream {
+                                    Some(gst) => gst,
+                                    None => {
+                                        command_failed = true;
+                                        error!("No stream to {}", if paused { "pause" } else { "resume" });
+                                        continue;
+                                    }
+                                };
+
+                                gst.set_paused(paused);
+                                stream_paused = paused;
+
+                                info!("[{}] Command processed", name);
+                            }
                             SocketListenerCommand::GetInfo { xids } => {
                                 info!("[GetInfo] Command received");
 
+                                let mut system = sysinfo::System::new();
+                                system.refresh_processes();
+
                                 // Find all PIDs of given XIDs
                                 let xid_pid: Vec<(xid, pid)> = xids
                                     .into_iter()
                                     .filter_map(|xid| {
-                                        if let Ok(Some(pid)) = x.pid_from_xid(xid) {
-                                            return Some((xid, pid));
+                                        if let Ok(Some(resolution)) = x.pid_from_xid(xid, &system) {
+                                            return Some((xid, resolution.pid));
                                         }
 
                                         None
@@ -188,23 +456,18 @@ impl CommandProcessor {
                                     .collect();
 
                                 // Do initial matching against returned Pulse PIDs
-                                let mut apps = pulse.get_audio_applications();
+                                let mut apps = pulse.get_audio_applications().await;
                                 let mut found_applications = vec![];
                                 for (xid, pid) in &xid_pid {
                                     if let Some(idx) = apps.iter().position(|app| app.pid == *pid) {
                                         let app = apps.remove(idx);
-                                        found_applications.push(Application {
-                                            name: app.name,
-                                            pid: *pid,
-                                            xid: *xid,
-                                        });
+                                        found_applications
+                                            .push(application_info(&app, *xid, &system));
                                     }
                                 }
 
                                 // If there are more Pulse applications to resolve, lookup process name and try to find pair with given PID for XID
                                 // Find all processes with given name
-                                let mut system = sysinfo::System::new();
-                                system.refresh_processes();
                                 let processes_with_cmd: Vec<(&Pid, &Process)> = system
                                     .processes()
                                     .iter()
@@ -223,11 +486,8 @@ impl CommandProcessor {
                                                 .find(|(_, pid)| *pid == proc_pid.as_u32())
                                             {
                                                 // Push the application and go to the next one
-                                                found_applications.push(Application {
-                                                    name: app.name.clone(),
-                                                    pid: app.pid,
-                                                    xid: *xid,
-                                                });
+                                                found_applications
+                                                    .push(application_info(app, *xid, &system));
                                                 break;
                                             }
                                         }
@@ -244,9 +504,230 @@ impl CommandProcessor {
                                         "[GetInfo] Command processed (applications found: {})",
                                         found_applications.len()
                                     ),
-                                    Err(e) => error!("Failed to send application data: {}", e),
+                                    Err(e) => {
+                                        command_failed = true;
+                                        error!("Failed to send application data: {}", e)
+                                    }
+                                }
+                            }
+                            SocketListenerCommand::ListWindows => {
+                                info!("[ListWindows] Command received");
+
+                                let windows = match x.list_windows() {
+                                    Ok(windows) => windows,
+                                    Err(e) => {
+                                        METRICS.record_x_error();
+                                        command_failed = true;
+                                        error!("Failed to list windows: {}", e);
+                                        continue;
+                                    }
+                                };
+
+                                match websocket.lock().await.window_list(&windows).await {
+                                    Ok(_) => info!(
+                                        "[ListWindows] Command processed (windows found: {})",
+                                        windows.len()
+                                    ),
+                                    Err(e) => {
+                                        command_failed = true;
+                                        error!("Failed to send window list: {}", e)
+                                    }
+                                }
+                            }
+                            SocketListenerCommand::ListMonitors => {
+                                info!("[ListMonitors] Command received");
+
+                                let monitors = match x.list_monitors() {
+                                    Ok(monitors) => monitors,
+                                    Err(e) => {
+                                        METRICS.record_x_error();
+                                        command_failed = true;
+                                        error!("Failed to list monitors: {}", e);
+                                        continue;
+                                    }
+                                };
+
+                                match websocket.lock().await.monitor_list(&monitors).await {
+                                    Ok(_) => info!(
+                                        "[ListMonitors] Command processed (monitors found: {})",
+                                        monitors.len()
+                                    ),
+                                    Err(e) => {
+                                        command_failed = true;
+                                        error!("Failed to send monitor list: {}", e)
+                                    }
                                 }
                             }
+                            SocketListenerCommand::GetPreview { xid, preview } => {
+                                info!("[GetPreview] Command received");
+
+                                let source = CaptureSource::Window(xid);
+                                let image = match x.take_screenshot(source, &preview) {
+                                    Ok(image) => image,
+                                    Err(e) => {
+                                        if let x::ScreenshotError::X(_) = e {
+                                            METRICS.record_x_error();
+                                        }
+                                        command_failed = true;
+                                        error!("Failed to take preview of {}: {}", xid, e);
+                                        continue;
+                                    }
+                                };
+
+                                match websocket
+                                    .lock()
+                                    .await
+                                    .window_preview(xid, &image, preview.format)
+                                    .await
+                                {
+                                    Ok(_) => info!("[GetPreview] Command processed"),
+                                    Err(e) => {
+                                        command_failed = true;
+                                        error!("Failed to send preview: {}", e)
+                                    }
+                                }
+                            }
+                            SocketListenerCommand::SetPrivacyPatterns { patterns } => {
+                                info!("[SetPrivacyPatterns] Command received");
+
+                                privacy.patterns = patterns;
+
+                                if let (Some(gst), Some(source)) = (&stream, current_source) {
+                                    if let Err(e) = x.watch_stacking(!privacy.patterns.is_empty()) {
+                                        METRICS.record_x_error();
+                                        command_failed = true;
+                                        error!("Failed to watch window stacking: {}", e);
+                                    }
+                                    privacy_active = update_privacy_shield(
+                                        &x,
+                                        gst,
+                                        source,
+                                        &privacy,
+                                        window_hidden,
+                                    );
+                                }
+
+                                info!("[SetPrivacyPatterns] Command processed");
+                            }
+                            SocketListenerCommand::GetStats => {
+                                info!("[GetStats] Command received");
+
+                                let stats = match &stream {
+                                    Some(gst) => gst.stats(),
+                                    None => {
+                                        command_failed = true;
+                                        error!("No stream to get statistics of");
+                                        continue;
+                                    }
+                                };
+
+                                METRICS.update_stream_stats(&stats);
+
+                                match websocket.lock().await.stream_stats(&stats).await {
+                                    Ok(_) => info!("[GetStats] Command processed"),
+                                    Err(e) => {
+                                        command_failed = true;
+                                        error!("Failed to send stream statistics: {}", e)
+                                    }
+                                }
+                            }
+                            SocketListenerCommand::DumpPipeline { svg } => {
+                                info!("[DumpPipeline] Command received");
+
+                                let graph = match &stream {
+                                    Some(gst) => {
+                                        gst.dump_graph("requested");
+                                        gst.graph()
+                                    }
+                                    None => {
+                                        command_failed = true;
+                                        error!("No pipeline to dump");
+                                        continue;
+                                    }
+                                };
+                                let rendered = match svg {
+                                    true => render_svg(&graph).await,
+                                    false => None,
+                                };
+
+                                match websocket
+                                    .lock()
+                                    .await
+                                    .pipeline_graph(&graph, rendered.as_deref())
+                                    .await
+                                {
+                                    Ok(_) => info!("[DumpPipeline] Command processed"),
+                                    Err(e) => {
+                                        command_failed = true;
+                                        error!("Failed to send pipeline graph: {}", e)
+                                    }
+                                }
+                            }
+                            SocketListenerCommand::SetOverlayText { text } => {
+                                info!("[SetOverlayText] Command received");
+
+                                match &stream {
+                                    Some(gst) => gst.set_overlay_text(text.as_deref()),
+                                    None => {
+                                        command_failed = true;
+                                        error!("No stream to set overlay text on");
+                                        continue;
+                                    }
+                                }
+
+                                info!("[SetOverlayText] Command processed");
+                            }
+                            SocketListenerCommand::SetPreviewInterval { interval } => {
+                                info!("[SetPreviewInterval] Command received");
+
+                                preview_interval = preview_interval_from_secs(interval);
+
+                                info!("[SetPreviewInterval] Command processed");
+                            }
+                            SocketListenerCommand::SetAudioMix { pid, gain, mute } => {
+                                info!("[SetAudioMix] Command received");
+
+                                let mix = AudioMix { gain, mute };
+                                audio_mixes.insert(pid, mix);
+
+                                if let (Some(gst), Some(current)) = (&stream, current_pid) {
+                                    if current == pid {
+                                        gst.set_audio_mix(mix, ducking, user_speaking);
+                                    }
+                                }
+
+                                info!("[SetAudioMix] Command processed");
+                            }
+                            SocketListenerCommand::SetDucking { enabled, level } => {
+                                info!("[SetDucking] Command received");
+
+                                ducking = DuckingSettings { enabled, level };
+
+                                if let (Some(gst), Some(pid)) = (&stream, current_pid) {
+                                    gst.set_audio_mix(
+                                        audio_mixes.get(&pid).copied().unwrap_or_default(),
+                                        ducking,
+                                        user_speaking,
+                                    );
+                                }
+
+                                info!("[SetDucking] Command processed");
+                            }
+                            SocketListenerCommand::SetSpeaking { speaking } => {
+                                user_speaking = speaking;
+
+                                if let (Some(gst), Some(pid)) = (&stream, current_pid) {
+                                    gst.set_audio_mix(
+                                        audio_mixes.get(&pid).copied().unwrap_or_default(),
+                                        ducking,
+                                        user_speaking,
+                                    );
+                                }
+                            }
+                        }
+
+                        if let Some(name) = current_command.take() {
+                            METRICS.record_command(name, !command_failed);
                         }
                     }
                     Err(e) => match e {
@@ -256,10 +737,163 @@ impl CommandProcessor {
                             break;
                         }
                         mpsc::error::TryRecvError::Empty => {
-                            // Check if time to send a stream preview
-                            let send_preview = if stream.is_some() {
+                            // Forward the latest audio levels so the client can show a meter, and stop
+                            // the stream if the pipeline died
+                            if let Some(gst) = &stream {
+                                let mut levels = None;
+                                for event in gst.poll_events() {
+                                    match event {
+                                        PipelineEvent::AudioLevels(l) => levels = Some(l),
+                                        PipelineEvent::Error(e) => {
+                                            error!("Pipeline failed, stopping stream: {}", e);
+                                            let _ = internal_command.insert(
+                                                SocketListenerCommand::StopStreamInternal {
+                                                    reason: StreamStopReason::PipelineError,
+                                                },
+                                            );
+                                        }
+                                        PipelineEvent::Eos => {
+                                            error!("Pipeline ended, stopping stream");
+                                            let _ = internal_command.insert(
+                                                SocketListenerCommand::StopStreamInternal {
+                                                    reason: StreamStopReason::PipelineEnded,
+                                                },
+                                            );
+                                        }
+                                    }
+                                }
+
+                                if let Some(levels) = levels {
+                                    if let Err(e) =
+                                        websocket.lock().await.audio_levels(&levels).await
+                                    {
+                                        error!("Failed to send audio levels: {}", e);
+                                    }
+                                }
+
+                                if internal_command.is_some() {
+                                    continue;
+                                }
+                            }
+
+                            // Report stream health periodically, metrics stay current even if reports are off
+                            if let Some(gst) = &stream {
+                                let period = match stats_interval.is_zero() {
+                                    true => Duration::from_secs(5),
+                                    false => stats_interval,
+                                };
+                                let due = last_stream_stats
+                                    .map_or(true, |last| last.elapsed() >= period);
+
+                                if due {
+                                    let _ = last_stream_stats.insert(time::Instant::now());
+                                    let stats = gst.stats();
+                                    METRICS.update_stream_stats(&stats);
+
+                                    if !stats_interval.is_zero() {
+                                        if let Err(e) =
+                                            websocket.lock().await.stream_stats(&stats).await
+                                        {
+                                            error!("Failed to send stream statistics: {}", e);
+                                        }
+                                    }
+                                }
+                            }
+
+                            // Follow the captured window so the stream doesn't break when it's hidden or closed,
+                            // and highlight clicks inside the capture
+                            if let (Some(gst), Some(source)) = (&stream, current_source) {
+                                let current = match source {
+                                    CaptureSource::Window(xid) => Some(xid),
+                                    CaptureSource::Area(_) => None,
+                                };
+
+                                let events = match x.poll_window_events() {
+                                    Ok(events) => events,
+                                    Err(e) => {
+                                        METRICS.record_x_error();
+                                        error!("Failed to poll window events: {}", e);
+                                        vec![]
+                                    }
+                                };
+
+                                // Window changes can reveal or hide protected windows
+                                let refresh_shield = events
+                                    .iter()
+                                    .any(|event| !matches!(event, WindowEvent::Clicked { .. }));
+
+                                for event in events {
+                                    match event {
+                                        WindowEvent::Destroyed(xid) if Some(xid) == current => {
+                                            info!("Captured window {} closed", xid);
+                                            let _ = internal_command.insert(
+                                                SocketListenerCommand::StopStreamInternal {
+                                                    reason: StreamStopReason::WindowClosed,
+                                                },
+                                            );
+                                        }
+                                        WindowEvent::Unmapped(xid) if Some(xid) == current => {
+                                            info!("Captured window {} hidden, showing placeholder", xid);
+                                            window_hidden = true;
+                                        }
+                                        WindowEvent::Mapped(xid) if Some(xid) == current => {
+                                            info!("Captured window {} shown again", xid);
+                                            window_hidden = false;
+                                        }
+                                        WindowEvent::Resized { xid, size } if Some(xid) == current => {
+                                            // The capture follows the window, the placeholder picks the new size up from its caps
+                                            debug!(
+                                                "Captured window {} resized to {}x{}",
+                                                xid, size.width, size.height
+                                            );
+                                        }
+                                        WindowEvent::Clicked { x: click_x, y: click_y } => {
+                                            match x.capture_position(source, click_x, click_y) {
+                                                Ok(Some(position)) => {
+                                                    gst.highlight_click(Some(position));
+                                                    let _ = click_highlight_until.insert(
+                                                        time::Instant::now()
+                                                            + Duration::from_millis(500),
+                                                    );
+                                                }
+                                                Ok(None) => {}
+                                                Err(e) => {
+                                                    METRICS.record_x_error();
+                                                    error!("Failed to locate click: {}", e)
+                                                }
+                                            }
+                                        }
+                                        _ => {}
+                                    }
+                                }
+
+                                if refresh_shield || gst.privacy_blur_pending() {
+                                    privacy_active = update_privacy_shield(
+                                        &x,
+                                        gst,
+                                        source,
+                                        &privacy,
+                                        window_hidden,
+                                    );
+                                }
+
+                                if let Some(until) = click_highlight_until {
+                                    if time::Instant::now() >= until {
+                                        click_highlight_until.take();
+                                        gst.highlight_click(None);
+                                    }
+                                }
+
+                                if internal_command.is_some() {
+                                    continue;
+                                }
+                            }
+
+                            // Check if time to send a stream preview, never while something is shielded
+                            let shown = !window_hidden && !privacy_active && !stream_paused;
+                            let send_preview = if stream.is_some() && shown {
                                 if let Some(last) = last_stream_preview {
-                                    time::Instant::now().duration_since(last) > Duration::from_secs(10 * 60)
+                                    time::Instant::now().duration_since(last) > preview_interval
                                 } else {
                                     true
                                 }
@@ -267,18 +901,34 @@ impl CommandProcessor {
                                 false
                             };
 
-                            if send_preview {
+                            if let (true, Some(source)) = (send_preview, current_source) {
                                 let _ = last_stream_preview.insert(time::Instant::now());
                                 info!("Sending stream preview");
-                                if let Err(e) = websocket
-                                    .lock()
-                                    .await
-                                    .stream_preview(
-                                        &x.take_screenshot(current_xid.unwrap()).unwrap(),
-                                    )
-                                    .await
-                                {
-                                    error!("Failed to send stream preview: {}", e);
+                                match x.take_screenshot(source, &preview_options) {
+                                    Ok(preview) => {
+                                        let mut hasher = DefaultHasher::new();
+                                        preview.hash(&mut hasher);
+                                        let hash = hasher.finish();
+
+                                        if last_preview_hash == Some(hash) {
+                                            info!("Stream preview unchanged, skipping");
+                                        } else if let Err(e) = websocket
+                                            .lock()
+                                            .await
+                                            .stream_preview(&preview, preview_options.format)
+                                            .await
+                                        {
+                                            error!("Failed to send stream preview: {}", e);
+                                        } else {
+                                            let _ = last_preview_hash.insert(hash);
+                                        }
+                                    }
+                                    Err(e) => {
+                                        if let x::ScreenshotError::X(_) = e {
+                                            METRICS.record_x_error();
+                                        }
+                                        error!("Failed to take stream preview: {}", e)
+                                    }
                                 }
                             }
 
@@ -301,3 +951,67 @@ impl CommandProcessor {
         }
     }
 }
+
+/// Hides protected windows covering the capture and applies the placeholder,
+/// returns whether anything is being shielded
+fn update_privacy_shield(
+    x: &XServerHandle,
+    gst: &GstHandle,
+    source: CaptureSource,
+    privacy: &PrivacyOptions,
+    window_hidden: bool,
+) -> bool {
+    let regions = match x.protected_regions(source, &privacy.patterns) {
+        Ok(regions) => regions,
+        Err(e) => {
+            // Fail closed, a broken check must not leak anything
+            METRICS.record_x_error();
+            error!("Failed to find protected windows, hiding stream: {}", e);
+            gst.show_placeholder(true);
+            return true;
+        }
+    };
+    let active = !regions.is_empty();
+
+    match privacy.mode {
+        PrivacyMode::Placeholder => gst.show_placeholder(window_hidden || active),
+        PrivacyMode::Blur => {
+            gst.show_placeholder(window_hidden);
+            gst.blur_regions(&regions);
+        }
+    }
+
+    active
+}
+
+/// Converts a requested preview interval, raising it to the minimum if it's shorter
+fn preview_interval_from_secs(secs: u64) -> Duration {
+    if secs < MIN_PREVIEW_INTERVAL_SECS {
+        warn!(
+            "Preview interval of {}s is too short, using {}s",
+            secs, MIN_PREVIEW_INTERVAL_SECS
+        );
+    }
+
+    Duration::from_secs(secs.max(MIN_PREVIEW_INTERVAL_SECS))
+}
+
+/// Builds the application info sent to the client for a Pulse application paired with a window
+fn application_info(app: &AudioApplication, xid: xid, system: &sysinfo::System) -> Application {
+    let binary_path = system
+        .process(Pid::from_u32(app.pid))
+        .map(|p| p.exe().to_string_lossy().to_string())
+        .filter(|path| !path.is_empty());
+
+    Application {
+        name: app.name.clone(),
+        pid: app.pid,
+        xid,
+        icon_name: app.icon_name.clone(),
+        binary: app.binary.clone(),
+        binary_path,
+        media_name: app.media_name.clone(),
+        playing: !app.corked,
+        sample_format: app.sample_spec.clone(),
+    }
+}
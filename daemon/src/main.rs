@@ -3,6 +3,7 @@ use std::{fs, panic, process, sync::{
     atomic::{AtomicBool, Ordering},
 }, time::Duration};
 use std::collections::HashMap;
+use std::path::PathBuf;
 use std::str::FromStr;
 
 use tokio::{
@@ -15,11 +16,44 @@ use tracing_log::LogTracer;
 use tracing_subscriber::{filter, Layer};
 use tracing_subscriber::layer::SubscriberExt;
 
-use tuxphones::{CommandProcessor, socket::WebSocket};
+use tuxphones::{CommandProcessor, metrics, socket::WebSocket};
 
 #[tokio::main]
 async fn main() {
-    initialize_logging();
+    let log_folder = initialize_logging();
+
+    // "TUX_GST_GRAPHS=true" writes GStreamer pipeline graphs into the log folder
+    let graph_dir = match std::env::var("TUX_GST_GRAPHS").unwrap_or_else(|_| "false".to_string()).parse::<bool>() {
+        Ok(true) => {
+            if log_folder.is_none() {
+                error!("Pipeline graphs need file logging, graphs disabled");
+            }
+            log_folder
+        }
+        Ok(false) => None,
+        Err(e) => {
+            error!("Invalid TUX_GST_GRAPHS value, graphs disabled: {}", e);
+            None
+        }
+    };
+
+    // "TUX_METRICS_PORT=9100" serves Prometheus metrics on localhost
+    let metrics_server = match std::env::var("TUX_METRICS_PORT") {
+        Ok(port) => match port.parse::<u16>() {
+            Ok(port) => match metrics::serve(port).await {
+                Ok(handle) => Some(handle),
+                Err(e) => {
+                    error!("Failed to serve metrics on port {}: {}", port, e);
+                    None
+                }
+            },
+            Err(e) => {
+                error!("Invalid TUX_METRICS_PORT value, metrics disabled: {}", e);
+                None
+            }
+        },
+        Err(_) => None,
+    };
 
     let run = Arc::new(AtomicBool::new(true));
     let r = Arc::clone(&run);
@@ -51,6 +85,7 @@ async fn main() {
         Arc::clone(&run),
         Duration::from_millis(500),
         socket_watcher.clone(),
+        graph_dir,
     );
 
     info!("Daemon started");
@@ -66,9 +101,13 @@ async fn main() {
 
     socket_watcher.lock().await.abort().await;
     command_processor.join().await;
+    if let Some(metrics_server) = metrics_server {
+        metrics_server.abort();
+    }
 }
 
-fn initialize_logging() {
+/// Sets up console and file logging, returns the folder log files are written to
+fn initialize_logging() -> Option<PathBuf> {
     // "TUX_LOG=category=level,category=level..."
     // "TUX_FILE_LOG=category=level,category=level..."
     // "TUX_FILE_PATH=/path/to/folder"
@@ -103,6 +142,7 @@ fn initialize_logging() {
         .collect();
 
     let mut file_subscribers = Vec::new();
+    let mut log_folder = None;
 
     if !file_categories.is_empty() {
         let file_path = std::env::var("TUX_FILE_PATH").unwrap_or_else(|_| "/tmp/tuxphones-{date}-{time}-{pid}".to_string());
@@ -123,6 +163,7 @@ fn initialize_logging() {
 
         match fs::create_dir_all(&file_path) {
             Ok(_) => {
+                log_folder = Some(file_path.clone());
                 if std::env::var("TUX_OPEN_LOG_ON_START").unwrap_or_else(|_| "false".to_string()).parse::<bool>().unwrap() {
                     match process::Command::new("xdg-open").arg(&file_path).spawn() {
                         Ok(_) => {},
@@ -176,4 +217,6 @@ fn initialize_logging() {
     }
 
     LogTracer::init().unwrap();
+
+    log_folder
 }
\ No newline at end of file
@@ -0,0 +1,291 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Instant,
+};
+
+use once_cell::sync::Lazy;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+    time::{timeout, Duration},
+};
+use tracing::{error, info};
+
+use crate::socket::StreamStats;
+
+/// Longest request line read, anything after it is ignored
+const MAX_REQUEST_LINE: u64 = 8 * 1024;
+/// Time a client gets to send its request line
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Daemon-wide metrics, updated from wherever the measured thing happens
+pub static METRICS: Lazy<Metrics> = Lazy::new(Metrics::default);
+
+#[derive(Default)]
+pub struct Metrics {
+    connections: AtomicU64,
+    /// Keyed by command name and whether it succeeded
+    commands: Mutex<BTreeMap<(&'static str, bool), u64>>,
+    stream_started: Mutex<Option<Instant>>,
+    /// Latest statistics of the running stream
+    stream_stats: Mutex<Option<StreamStats>>,
+    /// Pulse requests that got no reply, there is no reconnecting so these stay failed
+    pulse_unanswered: AtomicU64,
+    x_errors: AtomicU64,
+}
+
+impl Metrics {
+    pub fn set_connections(&self, count: usize) {
+        self.connections.store(count as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_command(&self, name: &'static str, success: bool) {
+        *self
+            .commands
+            .lock()
+            .unwrap()
+            .entry((name, success))
+            .or_default() += 1;
+    }
+
+    pub fn stream_started(&self) {
+        let _ = self.stream_started.lock().unwrap().insert(Instant::now());
+    }
+
+    pub fn stream_stopped(&self) {
+        self.stream_started.lock().unwrap().take();
+        self.stream_stats.lock().unwrap().take();
+    }
+
+    pub fn update_stream_stats(&self, stats: &StreamStats) {
+        let _ = self.stream_stats.lock().unwrap().insert(stats.clone());
+    }
+
+    pub fn record_pulse_unanswered(&self) {
+        self.pulse_unanswered.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_x_error(&self) {
+        self.x_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Formats all metrics in the Prometheus text format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        metric(
+            &mut out,
+            "connections",
+            "gauge",
+            "Connected WebSocket clients",
+            self.connections.load(Ordering::Relaxed),
+        );
+
+        let _ = writeln!(
+            out,
+            "# HELP tuxphones_commands_total Commands processed by type and outcome"
+        );
+        let _ = writeln!(out, "# TYPE tuxphones_commands_total counter");
+        for ((name, success), count) in self.commands.lock().unwrap().iter() {
+            let outcome = if *success { "ok" } else { "error" };
+            let _ = writeln!(
+                out,
+                "tuxphones_commands_total{{command=\"{}\",outcome=\"{}\"}} {}",
+                name, outcome, count
+            );
+        }
+
+        let started = *self.stream_started.lock().unwrap();
+        metric(
+            &mut out,
+            "stream_up",
+            "gauge",
+            "Whether a stream is running",
+            started.is_some() as u8,
+        );
+        metric(
+            &mut out,
+            "stream_uptime_seconds",
+            "gauge",
+            "Time since the current stream started",
+            started.map_or(0.0, |started| started.elapsed().as_secs_f64()),
+        );
+
+        // Zeros between streams keep the series continuous for rate()
+        let stats = self
+            .stream_stats
+            .lock()
+            .unwrap()
+            .clone()
+            .unwrap_or_default();
+        metric(
+            &mut out,
+            "stream_fps",
+            "gauge",
+            "Encoded video frames per second",
+            stats.fps,
+        );
+        metric(
+            &mut out,
+            "stream_video_bitrate_bits",
+            "gauge",
+            "Video bits per second leaving the full-size encoder",
+            stats.video_bitrate,
+        );
+        let _ = writeln!(
+            out,
+            "# HELP tuxphones_stream_layer_video_bitrate_bits Video bits per second leaving each simulcast layer's encoder"
+        );
+        let _ = writeln!(
+            out,
+            "# TYPE tuxphones_stream_layer_video_bitrate_bits gauge"
+        );
+        for (layer, bitrate) in stats.layer_bitrates.iter().enumerate() {
+            let _ = writeln!(
+                out,
+                "tuxphones_stream_layer_video_bitrate_bits{{layer=\"{}\"}} {}",
+                layer + 1,
+                bitrate
+            );
+        }
+        metric(
+            &mut out,
+            "stream_audio_bitrate_bits",
+            "gauge",
+            "Audio bits per second leaving the encoder",
+            stats.audio_bitrate,
+        );
+        metric(
+            &mut out,
+            "stream_encode_latency_seconds",
+            "gauge",
+            "Average time a frame spends in the video encoder",
+            stats.encode_latency_ms / 1000.0,
+        );
+        metric(
+            &mut out,
+            "stream_frames_encoded_total",
+            "counter",
+            "Video frames encoded in the current stream",
+            stats.frames_encoded,
+        );
+        metric(
+            &mut out,
+            "stream_dropped_frames_total",
+            "counter",
+            "Video frames dropped because the encoder fell behind",
+            stats.dropped_frames,
+        );
+        metric(
+            &mut out,
+            "stream_duplicated_frames_total",
+            "counter",
+            "Video frames repeated because the capture was slow",
+            stats.duplicated_frames,
+        );
+        metric(
+            &mut out,
+            "stream_rate_dropped_frames_total",
+            "counter",
+            "Video frames skipped because the capture was faster than the frame rate",
+            stats.rate_dropped_frames,
+        );
+        metric(
+            &mut out,
+            "stream_queue_overruns_total",
+            "counter",
+            "Times a pipeline queue filled up in the current stream",
+            stats.queue_overruns,
+        );
+        metric(
+            &mut out,
+            "stream_audio_underruns_total",
+            "counter",
+            "Gaps in captured audio in the current stream",
+            stats.audio_underruns,
+        );
+
+        metric(
+            &mut out,
+            "pulse_unanswered_requests_total",
+            "counter",
+            "Pulse requests that got no reply because the Pulse thread had stopped",
+            self.pulse_unanswered.load(Ordering::Relaxed),
+        );
+        metric(
+            &mut out,
+            "x_errors_total",
+            "counter",
+            "Failed X server requests",
+            self.x_errors.load(Ordering::Relaxed),
+        );
+
+        out
+    }
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str, value: impl std::fmt::Display) {
+    let _ = writeln!(out, "# HELP tuxphones_{} {}", name, help);
+    let _ = writeln!(out, "# TYPE tuxphones_{} {}", name, kind);
+    let _ = writeln!(out, "tuxphones_{} {}", name, value);
+}
+
+/// Serves `/metrics` over plain HTTP on localhost
+pub async fn serve(port: u16) -> Result<JoinHandle<()>, std::io::Error> {
+    let listener = TcpListener::bind(("127.0.0.1", port)).await?;
+    info!("Serving metrics on http://127.0.0.1:{}/metrics", port);
+
+    Ok(tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(handle_request(stream));
+        }
+    }))
+}
+
+async fn handle_request(mut stream: TcpStream) {
+    // Only the request line matters, e.g. "GET /metrics HTTP/1.1"
+    let mut request_line = String::new();
+    let mut reader = BufReader::new((&mut stream).take(MAX_REQUEST_LINE));
+    match timeout(REQUEST_TIMEOUT, reader.read_line(&mut request_line)).await {
+        Ok(Ok(_)) => {}
+        Ok(Err(e)) => {
+            error!("Failed to read metrics request: {}", e);
+            return;
+        }
+        Err(_) => {
+            error!("Metrics request timed out");
+            return;
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default();
+    let path = parts
+        .next()
+        .unwrap_or_default()
+        .split('?')
+        .next()
+        .unwrap_or_default();
+
+    let response = match (method, path) {
+        ("GET", "/metrics") => {
+            let body = METRICS.render();
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        }
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+    };
+
+    if let Err(e) = stream.write_all(response.as_bytes()).await {
+        error!("Failed to send metrics response: {}", e);
+    }
+}
@@ -1,25 +1,53 @@
-use std::{cell::RefCell, ops::Deref, sync::Arc};
+use std::{cell::RefCell, rc::Rc, sync::mpsc, thread};
 
-use crate::pid;
+use crate::{metrics::METRICS, pid, socket::AudioRouting};
+use sysinfo::{Pid, PidExt, ProcessExt, SystemExt};
+use tokio::sync::oneshot;
+use tracing::error;
 use libpulse_binding::{
     callbacks::ListResult,
     context::{Context, FlagSet as ContextFlagSet, State},
-    mainloop::threaded::Mainloop,
+    mainloop::standard::{IterateResult, Mainloop},
     operation::Operation,
 };
 
+/// Async handle to a Pulse connection owned by a dedicated thread, so that no blocking
+/// mainloop waits happen on the async runtime
 pub struct PulseHandle {
-    mainloop: Arc<RefCell<Mainloop>>,
-    context: Arc<RefCell<Context>>,
+    sender: Option<mpsc::Sender<PulseRequest>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+/// Requests handled by the Pulse thread, each carrying a channel for its reply
+enum PulseRequest {
+    GetAudioApplications(oneshot::Sender<Vec<AudioApplication>>),
+    SetupAudioCapture(
+        Option<String>,
+        AudioRouting,
+        oneshot::Sender<Result<(), PulseCaptureSetupError>>,
+    ),
+    TeardownAudioCapture(oneshot::Sender<()>),
+    StartCapture(pid, oneshot::Sender<Result<(), PulseCaptureError>>),
+    StopCapture(oneshot::Sender<()>),
+}
+
+/// Blocking Pulse connection, only ever used from the thread that created it. The mainloop is
+/// iterated on that thread too, so Pulse callbacks never run anywhere else
+struct PulseConnection {
+    /// Declared before the mainloop so it's dropped first
+    context: Context,
+    mainloop: Mainloop,
     audio_is_setup: bool,
     tuxphones_sink_module_index: Option<u32>,
-    combined_sink_index: Option<u32>,
+    /// Sink that captured applications are moved to, depends on the routing
+    capture_sink_index: Option<u32>,
     combined_sink_module_index: Option<u32>,
+    loopback_module_index: Option<u32>,
+    routing: AudioRouting,
+    passthrough_override: Option<String>,
     current_app_info: Option<CurrentAppInfo>,
 }
 
-unsafe impl Send for PulseHandle {}
-
 struct CurrentAppInfo {
     sink_input_restore_index: u32,
     index: u32,
@@ -37,12 +65,66 @@ pub struct AudioApplication {
     pub pid: pid,
     pub index: u32,
     pub sink_index: u32,
+    /// Binary name reported by the client library, if any
+    pub binary: Option<String>,
+    /// XDG icon name, if any
+    pub icon_name: Option<String>,
+    /// What the stream is playing, such as a track title
+    pub media_name: Option<String>,
+    /// Whether the stream is paused
+    pub corked: bool,
+    /// Sample format, channels, and rate, e.g. `s16le 2ch 44100Hz`
+    pub sample_spec: String,
+}
+
+/// Names the Discord client uses across release channels, compared case-insensitively
+const DISCORD_NAMES: [&str; 7] = [
+    "discord",
+    "discordcanary",
+    "discord-canary",
+    "discordptb",
+    "discord-ptb",
+    "discorddevelopment",
+    "discord-development",
+];
+
+/// Matches the Discord client's process names exactly, so apps that only mention Discord aren't hidden
+fn is_discord_name(name: &str) -> bool {
+    DISCORD_NAMES.contains(&name.to_lowercase().as_str())
+}
+
+impl AudioApplication {
+    /// Checks if this sink input belongs to the Discord client, either by its Pulse properties or by
+    /// any process in its parent chain being Discord. Capturing these would echo voice chat back into the stream.
+    pub fn is_discord(&self, system: &sysinfo::System) -> bool {
+        if is_discord_name(&self.name) || self.binary.as_deref().map_or(false, is_discord_name)
+        {
+            return true;
+        }
+
+        let mut next = Some(Pid::from_u32(self.pid));
+        while let Some(pid) = next {
+            let process = match system.process(pid) {
+                Some(p) => p,
+                None => break,
+            };
+
+            if is_discord_name(process.name()) {
+                return true;
+            }
+
+            next = process.parent();
+        }
+
+        false
+    }
 }
 
 #[derive(Debug)]
 pub enum PulseInitializationError {
+    ThreadSpawnErr,
     NoAlloc,
-    LoopStartErr(i32),
+    LoopErr(i32),
     ContextConnectErr(i32),
     ContextStateErr,
 }
@@ -50,8 +132,9 @@ pub enum PulseInitializationError {
 impl std::fmt::Display for PulseInitializationError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let str = match self {
+            PulseInitializationError::ThreadSpawnErr => "Unable to start Pulse thread".to_string(),
             PulseInitializationError::NoAlloc => "Unable to allocate".to_string(),
-            PulseInitializationError::LoopStartErr(code) => format!("Loop start error: {}", code),
+            PulseInitializationError::LoopErr(code) => format!("Loop error: {}", code),
             PulseInitializationError::ContextConnectErr(code) => {
                 format!("Context connection error: {}", code)
             }
@@ -65,6 +148,11 @@ impl std::fmt::Display for PulseInitializationError {
 pub enum PulseCaptureSetupError {
     NoPassthrough,
     NoDefaultSink,
+    LoopbackFailed,
+    CombineSinkFailed,
+    /// The sink captured applications would be moved to didn't show up
+    NoCaptureSink,
+    Disconnected,
 }
 
 impl std::fmt::Display for PulseCaptureSetupError {
@@ -72,6 +160,10 @@ impl std::fmt::Display for PulseCaptureSetupError {
         f.write_str(match self {
             PulseCaptureSetupError::NoPassthrough => "No passthrough sink found",
             PulseCaptureSetupError::NoDefaultSink => "No default sink found",
+            PulseCaptureSetupError::LoopbackFailed => "Unable to load loopback to passthrough sink",
+            PulseCaptureSetupError::CombineSinkFailed => "Unable to load combined sink",
+            PulseCaptureSetupError::NoCaptureSink => "Capture sink not found after loading it",
+            PulseCaptureSetupError::Disconnected => "Pulse thread not running",
         })
     }
 }
@@ -80,6 +172,8 @@ impl std::fmt::Display for PulseCaptureSetupError {
 pub enum PulseCaptureError {
     NotSetup,
     NoAppWithPid,
+    DiscordApp,
+    Disconnected,
 }
 
 impl std::fmt::Display for PulseCaptureError {
@@ -87,116 +181,226 @@ impl std::fmt::Display for PulseCaptureError {
         f.write_str(match self {
             PulseCaptureError::NotSetup => "Capture not setup",
             PulseCaptureError::NoAppWithPid => "No app with given PID found",
+            PulseCaptureError::DiscordApp => "Refusing to capture audio from Discord itself",
+            PulseCaptureError::Disconnected => "Pulse thread not running",
         })
     }
 }
 
 impl Drop for PulseHandle {
     fn drop(&mut self) {
-        self.stop_capture();
-        if self.audio_is_setup {
-            self.teardown_audio_capture();
-        }
+        // Closing the channel stops the Pulse thread, which cleans up its connection
+        self.sender.take();
+        if let Some(thread) = self.thread.take() {
+            let join = move || {
+                if thread.join().is_err() {
+                    error!("Pulse thread panicked");
+                }
+            };
 
-        self.mainloop.borrow_mut().lock();
-        self.context.borrow_mut().disconnect();
-        self.mainloop.borrow_mut().unlock();
-        self.mainloop.borrow_mut().stop();
+            // Don't block a runtime worker, the runtime still waits for the cleanup on shutdown
+            match tokio::runtime::Handle::try_current() {
+                Ok(runtime) => {
+                    runtime.spawn_blocking(join);
+                }
+                Err(_) => join(),
+            }
+        }
     }
 }
 
 impl PulseHandle {
-    /// Creates a new Pulse handle
-    pub fn new() -> Result<PulseHandle, PulseInitializationError> {
-        let mainloop = Arc::new(RefCell::new(match Mainloop::new() {
-            Some(l) => l,
-            None => return Err(PulseInitializationError::NoAlloc),
-        }));
+    /// Connects to the Pulse server on a new thread
+    pub async fn new() -> Result<PulseHandle, PulseInitializationError> {
+        let (sender, receiver) = mpsc::channel();
+        let (init_sender, init_receiver) = oneshot::channel();
+
+        let thread = thread::Builder::new()
+            .name("tuxphones-pulse".to_string())
+            .spawn(move || {
+                let mut connection = match PulseConnection::new() {
+                    Ok(c) => {
+                        let _ = init_sender.send(Ok(()));
+                        c
+                    }
+                    Err(e) => {
+                        let _ = init_sender.send(Err(e));
+                        return;
+                    }
+                };
 
-        match mainloop.borrow_mut().start() {
-            Ok(_) => {}
-            Err(e) => return Err(PulseInitializationError::LoopStartErr(e.0)),
+                while let Ok(request) = receiver.recv() {
+                    connection.handle(request);
+                }
+            })
+            .map_err(|_| PulseInitializationError::ThreadSpawnErr)?;
+
+        match init_receiver.await {
+            Ok(Ok(())) => Ok(PulseHandle {
+                sender: Some(sender),
+                thread: Some(thread),
+            }),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(PulseInitializationError::ThreadSpawnErr),
         }
+    }
 
-        // Lock mainloop to create context
-        mainloop.borrow_mut().lock();
+    /// Sends a request to the Pulse thread and waits for its reply, `None` if the thread is gone
+    async fn request<T>(
+        &self,
+        request: impl FnOnce(oneshot::Sender<T>) -> PulseRequest,
+    ) -> Option<T> {
+        let (reply_sender, reply_receiver) = oneshot::channel();
+        let reply = match self.sender.as_ref() {
+            Some(sender) if sender.send(request(reply_sender)).is_ok() => reply_receiver.await.ok(),
+            _ => None,
+        };
 
-        let context = Arc::new(RefCell::new(
-            match Context::new(mainloop.borrow_mut().deref(), "tuxphones") {
-                Some(c) => c,
-                None => {
-                    mainloop.borrow_mut().unlock();
-                    mainloop.borrow_mut().stop();
-                    return Err(PulseInitializationError::NoAlloc);
-                }
-            },
-        ));
+        if reply.is_none() {
+            METRICS.record_pulse_unanswered();
+        }
+        reply
+    }
 
-        // State callback to wait for connection
-        {
-            let ml_ref = Arc::clone(&mainloop);
-            let context_ref = Arc::clone(&context);
-            context
-                .borrow_mut()
-                .set_state_callback(Some(Box::new(move || {
-                    // Needs to be unsafe to be able to borrow mutably multiple times
-                    match unsafe { (*context_ref.as_ptr()).get_state() } {
-                        State::Ready | State::Failed | State::Terminated => unsafe {
-                            (*ml_ref.as_ptr()).signal(false);
-                        },
-                        _ => {}
-                    }
-                })));
+    /// Gets all applications that are producing audio, excluding Discord itself
+    pub async fn get_audio_applications(&self) -> Vec<AudioApplication> {
+        self.request(PulseRequest::GetAudioApplications)
+            .await
+            .unwrap_or_else(|| {
+                error!("Failed to get audio applications: Pulse thread not running");
+                vec![]
+            })
+    }
+
+    /// Adds sinks for audio capture
+    pub async fn setup_audio_capture(
+        &self,
+        passthrough_override: Option<&str>,
+        routing: AudioRouting,
+    ) -> Result<(), PulseCaptureSetupError> {
+        let passthrough_override = passthrough_override.map(str::to_string);
+        self.request(|reply| {
+            PulseRequest::SetupAudioCapture(passthrough_override, routing, reply)
+        })
+            .await
+            .unwrap_or(Err(PulseCaptureSetupError::Disconnected))
+    }
+
+    /// Removes audio capture sinks
+    pub async fn teardown_audio_capture(&self) {
+        if self.request(PulseRequest::TeardownAudioCapture).await.is_none() {
+            error!("Failed to tear down audio capture: Pulse thread not running");
         }
+    }
 
-        match context
-            .borrow_mut()
-            .connect(None, ContextFlagSet::NOFLAGS, None)
-        {
+    /// Starts capturing audio from the application with the given Pulse PID
+    pub async fn start_capture(&self, pid: pid) -> Result<(), PulseCaptureError> {
+        self.request(|reply| PulseRequest::StartCapture(pid, reply))
+            .await
+            .unwrap_or(Err(PulseCaptureError::Disconnected))
+    }
+
+    /// Stop capturing audio from application
+    pub async fn stop_capture(&self) {
+        if self.request(PulseRequest::StopCapture).await.is_none() {
+            error!("Failed to stop audio capture: Pulse thread not running");
+        }
+    }
+}
+
+impl Drop for PulseConnection {
+    fn drop(&mut self) {
+        self.stop_capture();
+        if self.audio_is_setup {
+            self.teardown_audio_capture();
+        }
+
+        self.context.disconnect();
+    }
+}
+
+impl PulseConnection {
+    /// Creates a new Pulse connection
+    fn new() -> Result<PulseConnection, PulseInitializationError> {
+        let mut mainloop = match Mainloop::new() {
+            Some(l) => l,
+            None => return Err(PulseInitializationError::NoAlloc),
+        };
+
+        let mut context = match Context::new(&mainloop, "tuxphones") {
+            Some(c) => c,
+            None => return Err(PulseInitializationError::NoAlloc),
+        };
+
+        match context.connect(None, ContextFlagSet::NOFLAGS, None) {
             Ok(_) => {}
-            Err(e) => {
-                mainloop.borrow_mut().unlock();
-                mainloop.borrow_mut().stop();
-                return Err(PulseInitializationError::ContextConnectErr(e.0));
-            }
+            Err(e) => return Err(PulseInitializationError::ContextConnectErr(e.0)),
         }
 
+        // Drive the mainloop until the connection settles
         loop {
-            match context.borrow_mut().get_state() {
+            match mainloop.iterate(true) {
+                IterateResult::Success(_) => {}
+                IterateResult::Quit(_) => return Err(PulseInitializationError::LoopErr(0)),
+                IterateResult::Err(e) => return Err(PulseInitializationError::LoopErr(e.0)),
+            }
+
+            match context.get_state() {
                 State::Ready => break,
                 State::Failed | State::Terminated => {
-                    mainloop.borrow_mut().unlock();
-                    mainloop.borrow_mut().stop();
-                    return Err(PulseInitializationError::ContextStateErr);
+                    return Err(PulseInitializationError::ContextStateErr)
                 }
-                _ => mainloop.borrow_mut().wait(),
+                _ => {}
             }
         }
-        context.borrow_mut().set_state_callback(None);
-
-        mainloop.borrow_mut().unlock();
 
-        Ok(PulseHandle {
-            context: Arc::clone(&context),
-            mainloop: Arc::clone(&mainloop),
+        Ok(PulseConnection {
+            context,
+            mainloop,
             audio_is_setup: false,
             tuxphones_sink_module_index: None,
-            combined_sink_index: None,
+            capture_sink_index: None,
             combined_sink_module_index: None,
+            loopback_module_index: None,
+            routing: AudioRouting::default(),
+            passthrough_override: None,
             current_app_info: None,
         })
     }
 
+    /// Runs a request from the `PulseHandle` and replies to it
+    fn handle(&mut self, request: PulseRequest) {
+        // A dropped receiver only means the caller stopped waiting
+        match request {
+            PulseRequest::GetAudioApplications(reply) => {
+                let _ = reply.send(self.get_audio_applications());
+            }
+            PulseRequest::SetupAudioCapture(passthrough_override, routing, reply) => {
+                let _ = reply.send(
+                    self.setup_audio_capture(passthrough_override.as_deref(), routing),
+                );
+            }
+            PulseRequest::TeardownAudioCapture(reply) => {
+                self.teardown_audio_capture();
+                let _ = reply.send(());
+            }
+            PulseRequest::StartCapture(pid, reply) => {
+                let _ = reply.send(self.start_capture(pid));
+            }
+            PulseRequest::StopCapture(reply) => {
+                self.stop_capture();
+                let _ = reply.send(());
+            }
+        }
+    }
+
     /// Gets the sinks connected to the Pulse server
     pub fn get_sinks(&mut self) -> Vec<BasicSinkInfo> {
-        self.mainloop.borrow_mut().lock();
-        let results = Arc::new(RefCell::new(Some(vec![])));
+        let results = Rc::new(RefCell::new(Some(vec![])));
 
-        let ml_ref = Arc::clone(&self.mainloop);
-        let results_ref = Arc::clone(&results);
+        let results_ref = Rc::clone(&results);
         let op = self
             .context
-            .borrow_mut()
             .introspect()
             .get_sink_info_list(move |res| match res {
                 ListResult::Item(info) => {
@@ -213,30 +417,32 @@ impl PulseHandle {
                             module: info.owner_module,
                         })
                 }
-                ListResult::End | ListResult::Error => unsafe {
-                    (*ml_ref.as_ptr()).signal(false);
-                },
+                ListResult::End | ListResult::Error => {}
             });
 
-        op_wait(&mut self.mainloop.borrow_mut(), &op);
-
-        self.mainloop.borrow_mut().unlock();
+        op_wait(&mut self.mainloop, &op);
 
         let res = results.borrow_mut().take().unwrap();
         res
     }
 
-    /// Gets all applications that are producing audio
+    /// Gets all applications that are producing audio, excluding Discord itself
     pub fn get_audio_applications(&mut self) -> Vec<AudioApplication> {
-        self.mainloop.borrow_mut().lock();
+        let mut system = sysinfo::System::new();
+        system.refresh_processes();
+
+        let mut apps = self.get_sink_inputs();
+        apps.retain(|app| !app.is_discord(&system));
+        apps
+    }
 
-        let results = Arc::new(RefCell::new(Some(vec![])));
+    /// Gets all sink inputs with an associated process
+    fn get_sink_inputs(&mut self) -> Vec<AudioApplication> {
+        let results = Rc::new(RefCell::new(Some(vec![])));
 
-        let ml_ref = Arc::clone(&self.mainloop);
-        let results_ref = Arc::clone(&results);
+        let results_ref = Rc::clone(&results);
         let op = self
             .context
-            .borrow_mut()
             .introspect()
             .get_sink_input_info_list(move |res| match res {
                 ListResult::Item(info) => {
@@ -253,17 +459,18 @@ impl PulseHandle {
                                 pid: pid.parse().unwrap(),
                                 index: info.index,
                                 sink_index: info.sink,
+                                binary: info.proplist.get_str("application.process.binary"),
+                                icon_name: info.proplist.get_str("application.icon_name"),
+                                media_name: info.proplist.get_str("media.name"),
+                                corked: info.corked,
+                                sample_spec: info.sample_spec.print(),
                             });
                     }
                 }
-                ListResult::End | ListResult::Error => unsafe {
-                    (*ml_ref.as_ptr()).signal(false);
-                },
+                ListResult::End | ListResult::Error => {}
             });
 
-        op_wait(&mut self.mainloop.borrow_mut(), &op);
-
-        self.mainloop.borrow_mut().unlock();
+        op_wait(&mut self.mainloop, &op);
 
         let res = results.borrow_mut().take().unwrap();
         res
@@ -273,24 +480,30 @@ impl PulseHandle {
     pub fn setup_audio_capture(
         &mut self,
         passthrough_override: Option<&str>,
+        routing: AudioRouting,
     ) -> Result<(), PulseCaptureSetupError> {
-        // Don't do the same thing twice
+        // Don't do the same thing twice, but rebuild if the routing changed
         if self.audio_is_setup {
-            return Ok(());
+            if self.routing == routing
+                && self.passthrough_override.as_deref() == passthrough_override
+            {
+                return Ok(());
+            }
+
+            self.stop_capture();
+            self.teardown_audio_capture();
         }
 
-        let passthrough_sink = match passthrough_override {
-            Some(s) => s.to_string(),
-            None => {
-                self.mainloop.borrow_mut().lock();
-                let result: Arc<RefCell<Option<String>>> = Arc::new(RefCell::new(None));
-                let ml_ref = Arc::clone(&self.mainloop);
-                let res_ref = Arc::clone(&result);
-                let op = self
-                    .context
-                    .borrow_mut()
-                    .introspect()
-                    .get_sink_info_by_name("@DEFAULT_SINK@", move |info| unsafe {
+        // Stream-only capture isn't played back anywhere
+        let passthrough_sink = match (routing, passthrough_override) {
+            (AudioRouting::StreamOnly, _) => None,
+            (_, Some(s)) => Some(s.to_string()),
+            (_, None) => {
+                let result: Rc<RefCell<Option<String>>> = Rc::new(RefCell::new(None));
+                let res_ref = Rc::clone(&result);
+                let op = self.context.introspect().get_sink_info_by_name(
+                    "@DEFAULT_SINK@",
+                    move |info| {
                         match info {
                             ListResult::Item(sink) => res_ref.borrow_mut().replace(
                                 sink.name
@@ -299,18 +512,16 @@ impl PulseHandle {
                             ),
                             ListResult::End | ListResult::Error => None,
                         };
+                    },
+                );
 
-                        (*ml_ref.as_ptr()).signal(false);
-                    });
-
-                op_wait(&mut self.mainloop.borrow_mut(), &op);
-                self.mainloop.borrow_mut().unlock();
+                op_wait(&mut self.mainloop, &op);
 
                 let res = match result.borrow_mut().take() {
                     Some(r) => r,
                     None => return Err(PulseCaptureSetupError::NoDefaultSink),
                 };
-                res
+                Some(res)
             }
         };
 
@@ -320,58 +531,86 @@ impl PulseHandle {
 
         for sink in self.get_sinks() {
             match &sink.name[..] {
-                "tuxphones" => tux_sink_found = true,
-                "tuxphones-combined" => tux_combined_sink_found = true,
-                n if n == passthrough_sink => passthrough_sink_found = true,
+                "tuxphones" => {
+                    tux_sink_found = true;
+                    self.tuxphones_sink_module_index = sink.module;
+                }
+                "tuxphones-combined" => {
+                    tux_combined_sink_found = true;
+                    self.combined_sink_module_index = sink.module;
+                }
+                n if Some(n) == passthrough_sink.as_deref() => passthrough_sink_found = true,
                 _ => {}
             }
         }
 
-        if !passthrough_sink_found {
+        if passthrough_sink.is_some() && !passthrough_sink_found {
             return Err(PulseCaptureSetupError::NoPassthrough);
         }
 
-        self.mainloop.borrow_mut().lock();
+        // A combined sink left over from an earlier session would keep playing to its passthrough sink
+        if routing != AudioRouting::Combined {
+            if let Some(idx) = self.combined_sink_module_index.take() {
+                self.unload_module(idx);
+            }
+        }
+
         if !tux_sink_found {
-            let ml_ref = Arc::clone(&self.mainloop);
-            let op = self.context.borrow_mut().introspect().load_module(
+            self.tuxphones_sink_module_index = self.load_module(
                 "module-null-sink",
                 "sink_name=tuxphones sink_properties=device.description=tuxphones",
-                move |_| unsafe {
-                    (*ml_ref.as_ptr()).signal(false);
-                },
             );
-
-            op_wait(&mut self.mainloop.borrow_mut(), &op);
         }
 
-        if !tux_combined_sink_found {
-            let ml_ref = Arc::clone(&self.mainloop);
-            // adjust_time=0 prevents a crash for some reason
-            let op = self.context.borrow_mut().introspect().load_module(
-                "module-combine-sink", 
-                &format!("sink_name=tuxphones-combined sink_properties=device.description=tuxphones-combined adjust_time=0 slaves=tuxphones,{}", passthrough_sink), 
-                move |_| unsafe {
-                    (*ml_ref.as_ptr()).signal(false);
+        match (routing, &passthrough_sink) {
+            (AudioRouting::Combined, Some(passthrough_sink)) if !tux_combined_sink_found => {
+                // adjust_time=0 prevents a crash for some reason
+                self.combined_sink_module_index = self.load_module(
+                    "module-combine-sink",
+                    &format!("sink_name=tuxphones-combined sink_properties=device.description=tuxphones-combined adjust_time=0 slaves=tuxphones,{}", passthrough_sink),
+                );
+
+                if self.combined_sink_module_index.is_none() {
+                    // Setup isn't marked done, so nothing else would unload the sink
+                    self.unload_capture_modules();
+                    return Err(PulseCaptureSetupError::CombineSinkFailed);
                 }
-            );
+            }
+            (AudioRouting::Monitor, Some(passthrough_sink)) => {
+                // A loopback resamples to follow the passthrough sink's clock instead of drifting like the combine sink
+                self.loopback_module_index = self.load_module(
+                    "module-loopback",
+                    &format!("source=tuxphones.monitor sink={} latency_msec=20 source_dont_move=true sink_dont_move=true", passthrough_sink),
+                );
 
-            op_wait(&mut self.mainloop.borrow_mut(), &op);
+                if self.loopback_module_index.is_none() {
+                    // Setup isn't marked done, so nothing else would unload the sink
+                    self.unload_capture_modules();
+                    return Err(PulseCaptureSetupError::LoopbackFailed);
+                }
+            }
+            _ => {}
         }
 
-        self.mainloop.borrow_mut().unlock();
-
         for sink in self.get_sinks() {
             match &sink.name[..] {
-                "tuxphones" => self.tuxphones_sink_module_index = Some(sink.module.unwrap()),
-                "tuxphones-combined" => {
-                    self.combined_sink_module_index = Some(sink.module.unwrap());
-                    self.combined_sink_index = Some(sink.index);
+                "tuxphones" if routing != AudioRouting::Combined => {
+                    self.capture_sink_index = Some(sink.index);
+                }
+                "tuxphones-combined" if routing == AudioRouting::Combined => {
+                    self.capture_sink_index = Some(sink.index);
                 }
                 _ => {}
             }
         }
 
+        if self.capture_sink_index.is_none() {
+            self.unload_capture_modules();
+            return Err(PulseCaptureSetupError::NoCaptureSink);
+        }
+
+        self.routing = routing;
+        self.passthrough_override = passthrough_override.map(str::to_string);
         self.audio_is_setup = true;
         Ok(())
     }
@@ -383,68 +622,84 @@ impl PulseHandle {
         }
 
         self.audio_is_setup = false;
+        self.unload_capture_modules();
+    }
 
-        self.mainloop.borrow_mut().lock();
-
-        if let Some(idx) = self.tuxphones_sink_module_index {
+    /// Unloads whichever capture modules are loaded, also used to undo a failed setup
+    fn unload_capture_modules(&mut self) {
+        // Loopback first, it depends on the tuxphones sink's monitor
+        if let Some(idx) = self.loopback_module_index.take() {
             self.unload_module(idx);
         }
 
-        if let Some(idx) = self.combined_sink_module_index {
+        // The combined sink plays to the tuxphones sink
+        if let Some(idx) = self.combined_sink_module_index.take() {
             self.unload_module(idx);
         }
 
-        self.tuxphones_sink_module_index = None;
-        self.combined_sink_index = None;
-        self.combined_sink_module_index = None;
+        if let Some(idx) = self.tuxphones_sink_module_index.take() {
+            self.unload_module(idx);
+        }
 
-        self.mainloop.borrow_mut().unlock();
+        self.capture_sink_index = None;
     }
 
-    /// Unloads modules
-    fn unload_module(&mut self, idx: u32) {
-        let ml_ref = Arc::clone(&self.mainloop);
+    /// Loads a module, returning its index if it loaded
+    fn load_module(&mut self, name: &str, argument: &str) -> Option<u32> {
+        let result: Rc<RefCell<Option<u32>>> = Rc::new(RefCell::new(None));
+        let res_ref = Rc::clone(&result);
         let op = self
             .context
-            .borrow_mut()
             .introspect()
-            .unload_module(idx, move |_| unsafe {
-                (*ml_ref.as_ptr()).signal(false);
+            .load_module(name, argument, move |idx| {
+                // Pulse reports failure as an invalid index
+                if idx != u32::MAX {
+                    res_ref.borrow_mut().replace(idx);
+                }
             });
 
-        op_wait(&mut self.mainloop.borrow_mut(), &op);
+        op_wait(&mut self.mainloop, &op);
+
+        let idx = result.borrow_mut().take();
+        if idx.is_none() {
+            error!("Failed to load {}", name);
+        }
+        idx
+    }
+
+    /// Unloads modules
+    fn unload_module(&mut self, idx: u32) {
+        let op = self.context.introspect().unload_module(idx, |_| {});
+
+        op_wait(&mut self.mainloop, &op);
     }
 
     /// Starts capturing audio from the application with the given Pulse PID
     pub fn start_capture(&mut self, pid: pid) -> Result<(), PulseCaptureError> {
-        if !self.audio_is_setup || self.combined_sink_module_index.is_none() {
+        if !self.audio_is_setup || self.capture_sink_index.is_none() {
             return Err(PulseCaptureError::NotSetup);
         }
 
-        for app in self.get_audio_applications() {
+        let mut system = sysinfo::System::new();
+        system.refresh_processes();
+
+        for app in self.get_sink_inputs() {
             if app.pid == pid {
-                self.mainloop.borrow_mut().lock();
+                if app.is_discord(&system) {
+                    return Err(PulseCaptureError::DiscordApp);
+                }
 
-                let ml_ref = Arc::clone(&self.mainloop);
                 self.current_app_info = Some(CurrentAppInfo {
                     sink_input_restore_index: app.sink_index,
                     index: app.index,
                 });
-                let op = self
-                    .context
-                    .borrow_mut()
-                    .introspect()
-                    .move_sink_input_by_index(
-                        app.index,
-                        self.combined_sink_index.unwrap(),
-                        Some(Box::new(move |_| unsafe {
-                            (*ml_ref.as_ptr()).signal(false);
-                        })),
-                    );
-
-                op_wait(&mut self.mainloop.borrow_mut(), &op);
-
-                self.mainloop.borrow_mut().unlock();
+                let op = self.context.introspect().move_sink_input_by_index(
+                    app.index,
+                    self.capture_sink_index.unwrap(),
+                    None,
+                );
+
+                op_wait(&mut self.mainloop, &op);
 
                 return Ok(());
             }
@@ -455,33 +710,29 @@ impl PulseHandle {
 
     /// Stop capturing audio from application
     pub fn stop_capture(&mut self) {
-        self.mainloop.borrow_mut().lock();
-
         if let Some(info) = &self.current_app_info {
-            let ml_ref = Arc::clone(&self.mainloop);
-            let op = self
-                .context
-                .borrow_mut()
-                .introspect()
-                .move_sink_input_by_index(
-                    info.index,
-                    info.sink_input_restore_index,
-                    Some(Box::new(move |_| unsafe {
-                        (*ml_ref.as_ptr()).signal(false);
-                    })),
-                );
+            let op = self.context.introspect().move_sink_input_by_index(
+                info.index,
+                info.sink_input_restore_index,
+                None,
+            );
 
-            op_wait(&mut self.mainloop.borrow_mut(), &op);
+            op_wait(&mut self.mainloop, &op);
         }
 
         self.current_app_info = None;
-        self.mainloop.borrow_mut().unlock();
     }
 }
 
-/// Wait for operation to complete
+/// Runs the mainloop until the operation completes, its callbacks run in here
 fn op_wait<T: ?Sized>(ml: &mut Mainloop, op: &Operation<T>) {
     while op.get_state() == libpulse_binding::operation::State::Running {
-        ml.wait()
+        match ml.iterate(true) {
+            IterateResult::Success(_) => {}
+            IterateResult::Quit(_) | IterateResult::Err(_) => {
+                error!("Pulse mainloop stopped while waiting for an operation");
+                break;
+            }
+        }
     }
 }
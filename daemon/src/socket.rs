@@ -1,4 +1,4 @@
-use crate::{pid, xid};
+use crate::{metrics::METRICS, pid, x::PidSource, xid};
 use async_tungstenite::{
     tokio::{accept_async, TokioAdapter},
     tungstenite::{Error, Message},
@@ -6,7 +6,7 @@ use async_tungstenite::{
 };
 use futures_util::{stream::SplitSink, SinkExt, StreamExt};
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, fmt::Display, net::SocketAddr, sync::Arc};
+use std::{collections::HashMap, fmt::Display, net::SocketAddr, num::NonZeroU32, sync::Arc};
 use tokio::{
     net::{TcpListener, TcpStream},
     sync::{mpsc, Mutex},
@@ -41,6 +41,225 @@ pub struct StreamResolutionInformation {
     pub is_fixed: bool,
 }
 
+/// How captured audio is routed between the stream and local playback
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum AudioRouting {
+    /// A combine sink plays the application on both the stream and the passthrough sink
+    #[default]
+    Combined,
+    /// The application is only heard on the stream
+    StreamOnly,
+    /// The stream sink's monitor is looped back to the passthrough sink
+    Monitor,
+}
+
+/// A Pulse source mixed into the stream audio alongside the captured application
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct ExtraAudioSource {
+    /// Pulse source name, such as a microphone or another sink's monitor
+    pub name: String,
+    /// Linear gain, 1.0 leaves the audio unchanged
+    #[serde(default = "default_gain")]
+    pub gain: f64,
+}
+
+fn default_gain() -> f64 {
+    1.0
+}
+
+/// Preset audio encoder settings for common kinds of content
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum AudioProfile {
+    /// Speech, low bitrate with discontinuous transmission
+    Voice,
+    #[default]
+    Balanced,
+    /// High bitrate stereo for music and games
+    Music,
+}
+
+/// What the captured video mostly shows, picks encoder and scaling trade-offs like WebRTC content hints
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ContentHint {
+    /// Games and video, favors latency and smooth motion
+    #[default]
+    Motion,
+    /// Photos and drawings, favors sharpness over framerate
+    Detail,
+    /// Slides, documents, and code, favors legible text at a low framerate
+    Text,
+}
+
+/// Opus signal type hint
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OpusAudioType {
+    Generic,
+    Voice,
+}
+
+/// Per-stream Opus encoder overrides, unset fields come from the audio profile
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct OpusOptions {
+    /// Bitrate in bits per second
+    pub bitrate: Option<i32>,
+    pub audio_type: Option<OpusAudioType>,
+    /// Frame size in milliseconds
+    pub frame_size: Option<f64>,
+    /// In-band forward error correction
+    pub fec: Option<bool>,
+    /// Expected packet loss used to tune FEC, 0-100
+    pub packet_loss_percentage: Option<i32>,
+    /// Discontinuous transmission, stops sending during silence
+    pub dtx: Option<bool>,
+    /// Encoder complexity, 0-10
+    pub complexity: Option<i32>,
+}
+
+/// What part of the screen a stream captures
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum CaptureTarget {
+    /// The window given by the stream's XID
+    #[default]
+    Window,
+    /// The whole root window, spanning all monitors
+    Screen,
+    /// A single RandR monitor
+    Monitor { name: String },
+    /// A rectangle of the root window
+    Region {
+        x: i16,
+        y: i16,
+        width: u16,
+        height: u16,
+    },
+}
+
+/// Image format of previews
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PreviewFormat {
+    Jpeg,
+    Png,
+    Webp,
+}
+
+impl PreviewFormat {
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            PreviewFormat::Jpeg => "image/jpeg",
+            PreviewFormat::Png => "image/png",
+            PreviewFormat::Webp => "image/webp",
+        }
+    }
+}
+
+/// How previews are sized and encoded
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(default)]
+pub struct PreviewOptions {
+    /// The preview is scaled down to fit within these bounds, never up
+    pub max_width: NonZeroU32,
+    pub max_height: NonZeroU32,
+    pub format: PreviewFormat,
+    /// Lossy encoding quality, 1-100, ignored for PNG
+    pub quality: u8,
+}
+
+impl Default for PreviewOptions {
+    fn default() -> Self {
+        PreviewOptions {
+            max_width: NonZeroU32::new(512).unwrap(),
+            max_height: NonZeroU32::new(512).unwrap(),
+            format: PreviewFormat::Jpeg,
+            quality: 75,
+        }
+    }
+}
+
+/// How protected windows are hidden from the stream
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum PrivacyMode {
+    /// Replaces the whole stream with the placeholder
+    #[default]
+    Placeholder,
+    /// Blurs the part of the stream covered by protected windows
+    Blur,
+}
+
+/// Windows that must never be visible in the stream
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+#[serde(default)]
+pub struct PrivacyOptions {
+    /// Case-insensitive `WM_CLASS` instance or class patterns, `*` matches anything
+    pub patterns: Vec<String>,
+    pub mode: PrivacyMode,
+}
+
+/// Where an overlay is drawn on the video
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OverlayPosition {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+    Center,
+}
+
+/// Text, clock, and image drawn over the outgoing video
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct OverlayOptions {
+    /// Text such as the streamer's name, can be changed with `SetOverlayText`
+    pub text: Option<String>,
+    pub text_position: OverlayPosition,
+    /// Pango font description used for text and the clock
+    pub font: String,
+    /// Shows the local time
+    pub clock: bool,
+    pub clock_position: OverlayPosition,
+    /// Path to an image such as a logo PNG
+    pub image: Option<String>,
+    pub image_position: OverlayPosition,
+    /// Image opacity, 0-1
+    pub image_alpha: f64,
+}
+
+impl Default for OverlayOptions {
+    fn default() -> Self {
+        OverlayOptions {
+            text: None,
+            text_position: OverlayPosition::TopLeft,
+            font: "Sans 24".to_string(),
+            clock: false,
+            clock_position: OverlayPosition::TopRight,
+            image: None,
+            image_position: OverlayPosition::BottomRight,
+            image_alpha: 1.0,
+        }
+    }
+}
+
+/// Why the daemon stopped a stream on its own
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum StreamStopReason {
+    /// The captured window was closed
+    WindowClosed,
+    /// The pipeline failed, such as an element erroring out
+    PipelineError,
+    /// The pipeline ran out of data
+    PipelineEnded,
+    #[default]
+    Other,
+}
+
 /// Holds RTC ICE information
 #[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
 #[serde(tag = "type")]
@@ -51,7 +270,7 @@ pub struct IceData {
 }
 
 /// Commands that can be received from the client plugin
-#[derive(Deserialize, Debug, PartialEq, Eq)]
+#[derive(Deserialize, Debug, PartialEq)]
 #[serde(tag = "type")]
 pub enum SocketListenerCommand {
     /// Starts a new soundshare stream
@@ -70,20 +289,164 @@ pub enum SocketListenerCommand {
         secret_key: Vec<u8>,
         /// The associated voice chat's SSRC
         voice_ssrc: u32,
-        /// The base SSRC for creating a stream, needs to be offset to get video (+1) and RTX (+2) SSRCs
+        /// The base SSRC for creating a stream, used for audio and offset to get video (+1) and RTX (+2) SSRCs
         base_ssrc: u32,
+        /// SSRC the client already uses for the stream's audio, defaults to the base SSRC
+        #[serde(default)]
+        audio_ssrc: Option<u32>,
         ip: String,
         port: u16,
+        /// Sink to keep playing captured audio on locally, defaults to the default sink
+        #[serde(default)]
+        passthrough_sink: Option<String>,
+        /// How captured audio is routed
+        #[serde(default)]
+        audio_routing: AudioRouting,
+        /// Extra Pulse source to mix into the stream audio
+        #[serde(default)]
+        extra_source: Option<ExtraAudioSource>,
+        /// Base audio encoder settings
+        #[serde(default)]
+        audio_profile: AudioProfile,
+        /// Audio encoder overrides on top of the profile
+        #[serde(default)]
+        opus: OpusOptions,
+        /// What to capture, defaults to the window given by `xid`
+        #[serde(default)]
+        capture: CaptureTarget,
+        /// How stream previews are sized and encoded
+        #[serde(default)]
+        preview: PreviewOptions,
+        /// Seconds between stream previews, at least 5
+        #[serde(default = "default_preview_interval")]
+        preview_interval: u64,
+        /// Draws the mouse cursor into the stream
+        #[serde(default)]
+        show_cursor: bool,
+        /// Draws a ring wherever the user clicks inside the capture
+        #[serde(default)]
+        highlight_clicks: bool,
+        /// Hides protected windows such as password managers
+        #[serde(default)]
+        privacy: PrivacyOptions,
+        /// Text, clock, and image drawn over the video
+        #[serde(default)]
+        overlay: OverlayOptions,
+        /// Seconds between `StreamStats` messages, 0 disables them
+        #[serde(default = "default_stats_interval")]
+        stats_interval: u64,
+        /// What the video mostly shows
+        #[serde(default)]
+        content_hint: ContentHint,
+        /// Video layers at halving resolutions for viewers with less bandwidth, at most 3, 1 or less disables simulcast
+        #[serde(default)]
+        simulcast_layers: u8,
+        /// Bits per second of the full-size video, each smaller simulcast layer gets a quarter of the one above
+        #[serde(default = "default_video_bitrate")]
+        video_bitrate: u32,
     },
     /// Stops the currently-running stream
     StopStream,
+    /// Sends a paused image and silence instead of the capture, keeping the stream connected
+    PauseStream,
+    /// Goes back to sending the capture after `PauseStream`
+    ResumeStream,
     /// Internal stop stream command, notifies client plugin
-    StopStreamInternal,
+    StopStreamInternal {
+        #[serde(default)]
+        reason: StreamStopReason,
+    },
     /// Gets info on which windows can have sound captured
     GetInfo {
         /// XIDs available to Discord
         xids: Vec<xid>,
     },
+    /// Sets the gain and mute state of an application's audio in the stream mix only
+    SetAudioMix {
+        /// Pulse PID
+        pid: pid,
+        /// Linear gain, 1.0 leaves the audio unchanged
+        gain: f64,
+        mute: bool,
+    },
+    /// Configures ducking of captured audio while the user is speaking
+    SetDucking {
+        enabled: bool,
+        /// Linear gain applied while ducked
+        level: f64,
+    },
+    /// Tells the daemon whether the user is currently speaking in voice chat
+    SetSpeaking {
+        speaking: bool,
+    },
+    /// Lists the top-level windows on the X server
+    ListWindows,
+    /// Lists the monitors that can be captured
+    ListMonitors,
+    /// Takes a preview of any window, independent of the stream
+    GetPreview {
+        xid: xid,
+        #[serde(default)]
+        preview: PreviewOptions,
+    },
+    /// Replaces the privacy shield's `WM_CLASS` patterns, the mode is fixed for the stream
+    SetPrivacyPatterns {
+        patterns: Vec<String>,
+    },
+    /// Gets the current stream's statistics
+    GetStats,
+    /// Gets the current pipeline's graph, optionally rendered to SVG with Graphviz
+    DumpPipeline {
+        #[serde(default)]
+        svg: bool,
+    },
+    /// Changes the overlay text of the current stream, `None` hides it
+    SetOverlayText {
+        text: Option<String>,
+    },
+    /// Changes how often previews of the current stream are sent
+    SetPreviewInterval {
+        /// Seconds between stream previews, at least 5
+        interval: u64,
+    },
+}
+
+impl SocketListenerCommand {
+    /// The command's type as sent by the client
+    pub fn name(&self) -> &'static str {
+        match self {
+            SocketListenerCommand::StartStream { .. } => "StartStream",
+            SocketListenerCommand::StopStream => "StopStream",
+            SocketListenerCommand::PauseStream => "PauseStream",
+            SocketListenerCommand::ResumeStream => "ResumeStream",
+            SocketListenerCommand::StopStreamInternal { .. } => "StopStreamInternal",
+            SocketListenerCommand::GetInfo { .. } => "GetInfo",
+            SocketListenerCommand::SetAudioMix { .. } => "SetAudioMix",
+            SocketListenerCommand::SetDucking { .. } => "SetDucking",
+            SocketListenerCommand::SetSpeaking { .. } => "SetSpeaking",
+            SocketListenerCommand::ListWindows => "ListWindows",
+            SocketListenerCommand::ListMonitors => "ListMonitors",
+            SocketListenerCommand::GetPreview { .. } => "GetPreview",
+            SocketListenerCommand::SetPrivacyPatterns { .. } => "SetPrivacyPatterns",
+            SocketListenerCommand::GetStats => "GetStats",
+            SocketListenerCommand::DumpPipeline { .. } => "DumpPipeline",
+            SocketListenerCommand::SetOverlayText { .. } => "SetOverlayText",
+            SocketListenerCommand::SetPreviewInterval { .. } => "SetPreviewInterval",
+        }
+    }
+}
+
+fn default_preview_interval() -> u64 {
+    10 * 60
+}
+
+/// x264enc's own default, which streams used before the bitrate could be set
+fn default_video_bitrate() -> u32 {
+    2_048_000
+}
+
+fn default_stats_interval() -> u64 {
+    5
 }
 
 #[derive(Serialize)]
@@ -98,16 +461,165 @@ pub struct Application {
     pub name: String,
     pub pid: pid,
     pub xid: xid,
+    /// XDG icon name
+    pub icon_name: Option<String>,
+    /// Binary name reported to Pulse
+    pub binary: Option<String>,
+    /// Full path of the executable
+    pub binary_path: Option<String>,
+    /// What the application is playing, such as a track title
+    pub media_name: Option<String>,
+    /// Whether audio is currently playing (not corked)
+    pub playing: bool,
+    /// Sample format, channels, and rate, e.g. `s16le 2ch 44100Hz`
+    pub sample_format: String,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type")]
+struct WindowList<'a> {
+    windows: &'a Vec<WindowInfo>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct WindowInfo {
+    pub xid: xid,
+    /// `_NET_WM_NAME` or `WM_NAME`
+    pub title: Option<String>,
+    /// Instance part of `WM_CLASS`
+    pub instance: Option<String>,
+    /// Class part of `WM_CLASS`
+    pub class: Option<String>,
+    pub pid: Option<pid>,
+    /// How the PID was found
+    pub pid_source: Option<PidSource>,
+    /// Position relative to the root window
+    pub x: i16,
+    pub y: i16,
+    pub width: u16,
+    pub height: u16,
+    /// Whether the window is mapped and viewable
+    pub visible: bool,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type")]
+struct MonitorList<'a> {
+    monitors: &'a Vec<MonitorInfo>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct MonitorInfo {
+    /// RandR monitor name, usually the output name such as `DP-1`
+    pub name: String,
+    /// Position on the root window
+    pub x: i16,
+    pub y: i16,
+    pub width: u16,
+    pub height: u16,
+    pub primary: bool,
 }
 
 #[derive(Serialize, Debug)]
 #[serde(tag = "type")]
-pub struct StreamStop {}
+pub struct StreamStop {
+    reason: StreamStopReason,
+}
 
+/// A preview of the running stream for Discord's stream thumbnail
+///
+/// Replaced the old `jpg` field, clients reading it must update together with the daemon.
 #[derive(Serialize, Debug)]
 #[serde(tag = "type")]
 pub struct StreamPreview {
-    jpg: String,
+    /// Base64 encoded image
+    data: String,
+    mime_type: &'static str,
+}
+
+/// Sent once a stream's pipeline is built so the client can announce its video layers to Discord
+#[derive(Serialize, Debug)]
+#[serde(tag = "type")]
+pub struct StreamLayers<'a> {
+    /// RTC connection of the `StartStream` that built the layers
+    rtc_connection_id: &'a str,
+    audio_ssrc: u32,
+    /// Full-size video first
+    layers: &'a [StreamLayer],
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct StreamLayer {
+    /// Simulcast restriction ID, the layer's quality as a string
+    pub rid: String,
+    /// Percentage of the full size
+    pub quality: u8,
+    pub ssrc: u32,
+    pub rtx_ssrc: u32,
+    /// Bits per second the layer's encoder targets
+    pub max_bitrate: u32,
+    pub max_framerate: i32,
+    /// Largest size of the layer, 0 if it follows the capture
+    pub width: u16,
+    pub height: u16,
+    /// Whether the size is fixed or follows the capture
+    pub is_fixed: bool,
+}
+
+/// Preview requested through `GetPreview`
+#[derive(Serialize, Debug)]
+#[serde(tag = "type")]
+pub struct WindowPreview {
+    xid: xid,
+    /// Base64 encoded image
+    data: String,
+    mime_type: &'static str,
+}
+
+/// Health of the running stream, rates are averaged since the previous report
+#[derive(Serialize, Debug, Clone, Default)]
+#[serde(tag = "type")]
+pub struct StreamStats {
+    /// Encoded video frames per second
+    pub fps: f64,
+    /// Bits per second leaving the full-size video encoder
+    pub video_bitrate: u64,
+    pub audio_bitrate: u64,
+    /// Bits per second leaving each extra simulcast layer's encoder, smallest layer last
+    pub layer_bitrates: Vec<u64>,
+    /// Average time a frame spends in the video encoder
+    pub encode_latency_ms: f64,
+    /// Totals since the stream started
+    pub frames_encoded: u64,
+    /// Frames thrown away before encoding because the encoder fell behind
+    pub dropped_frames: u64,
+    /// Frames repeated to keep the frame rate while the capture is slow
+    pub duplicated_frames: u64,
+    /// Frames skipped to keep the frame rate while the capture is faster
+    pub rate_dropped_frames: u64,
+    pub queue_overruns: u64,
+    /// Gaps in the captured audio
+    pub audio_underruns: u64,
+    /// DiscordStreamer's own statistics, if it exposes any
+    pub streamer: Option<serde_json::Value>,
+}
+
+/// Reply to `DumpPipeline`
+#[derive(Serialize, Debug)]
+#[serde(tag = "type")]
+pub struct PipelineGraph<'a> {
+    /// Graphviz source
+    dot: &'a str,
+    /// Only present if requested and Graphviz could render it
+    svg: Option<&'a str>,
+}
+
+/// Audio levels of the outgoing stream mix in dB, one entry per channel
+#[derive(Serialize, Debug)]
+#[serde(tag = "type")]
+pub struct AudioLevels {
+    pub rms: Vec<f64>,
+    pub peak: Vec<f64>,
 }
 
 impl Display for SocketListenerCreationError {
@@ -174,6 +686,7 @@ impl WebSocket {
         for rm in to_remove {
             conn.remove(&rm);
         }
+        METRICS.set_connections(conn.len());
 
         Ok(())
     }
@@ -182,17 +695,66 @@ impl WebSocket {
         self.send(&ApplicationList { apps }).await
     }
 
-    pub async fn stream_stop_internal(&self) -> Result<(), Error> {
-        self.send(&StreamStop {}).await
+    pub async fn window_list(&self, windows: &Vec<WindowInfo>) -> Result<(), Error> {
+        self.send(&WindowList { windows }).await
+    }
+
+    pub async fn monitor_list(&self, monitors: &Vec<MonitorInfo>) -> Result<(), Error> {
+        self.send(&MonitorList { monitors }).await
     }
 
-    pub async fn stream_preview(&self, data: &Vec<u8>) -> Result<(), Error> {
+    pub async fn stream_stop_internal(&self, reason: StreamStopReason) -> Result<(), Error> {
+        self.send(&StreamStop { reason }).await
+    }
+
+    pub async fn stream_preview(&self, data: &Vec<u8>, format: PreviewFormat) -> Result<(), Error> {
         self.send(&StreamPreview {
-            jpg: base64::encode(data),
+            data: base64::encode(data),
+            mime_type: format.mime_type(),
+        })
+        .await
+    }
+
+    pub async fn window_preview(
+        &self,
+        xid: xid,
+        data: &Vec<u8>,
+        format: PreviewFormat,
+    ) -> Result<(), Error> {
+        self.send(&WindowPreview {
+            xid,
+            data: base64::encode(data),
+            mime_type: format.mime_type(),
+        })
+        .await
+    }
+
+    pub async fn stream_layers(
+        &self,
+        rtc_connection_id: &str,
+        audio_ssrc: u32,
+        layers: &[StreamLayer],
+    ) -> Result<(), Error> {
+        self.send(&StreamLayers {
+            rtc_connection_id,
+            audio_ssrc,
+            layers,
         })
         .await
     }
 
+    pub async fn pipeline_graph(&self, dot: &str, svg: Option<&str>) -> Result<(), Error> {
+        self.send(&PipelineGraph { dot, svg }).await
+    }
+
+    pub async fn stream_stats(&self, stats: &StreamStats) -> Result<(), Error> {
+        self.send(stats).await
+    }
+
+    pub async fn audio_levels(&self, levels: &AudioLevels) -> Result<(), Error> {
+        self.send(levels).await
+    }
+
     /// Kills the WebSocket thread and closes everything up
     pub async fn abort(&mut self) {
         if let Some(thread) = self.thread.take() {
@@ -217,7 +779,11 @@ impl WebSocket {
 
         let (write, mut read) = ws_stream.split();
 
-        connections.lock().await.insert(addr, write);
+        {
+            let mut connections = connections.lock().await;
+            connections.insert(addr, write);
+            METRICS.set_connections(connections.len());
+        }
 
         while let Some(msg) = read.next().await {
             let msg = msg?;
@@ -243,13 +809,14 @@ impl WebSocket {
         sender: CommandSender,
     ) {
         let addr = stream.peer_addr().unwrap();
-        if let Err(e) = Self::handle_connection(stream, connections.clone(), sender).await {
-            match e {
-                Error::ConnectionClosed | Error::Protocol(_) | Error::Utf8 => {
-                    connections.lock().await.remove(&addr);
-                }
-                err => error!("Error processing connection: {}", err),
+        match Self::handle_connection(stream, connections.clone(), sender).await {
+            // The client closed the connection cleanly
+            Ok(_) | Err(Error::ConnectionClosed | Error::Protocol(_) | Error::Utf8) => {
+                let mut connections = connections.lock().await;
+                connections.remove(&addr);
+                METRICS.set_connections(connections.len());
             }
+            Err(err) => error!("Error processing connection: {}", err),
         }
     }
 }
@@ -0,0 +1,169 @@
+/// Most video layers sent at once when simulcasting
+pub const MAX_SIMULCAST_LAYERS: u8 = 3;
+
+/// SSRCs used by one stream, derived from the base SSRC Discord assigns to it
+///
+/// Audio takes the base unless the client picked its own, video the next one and video
+/// retransmissions (RTX) the one after. Extra simulcast layers continue with a video and RTX
+/// pair each.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamSSRCs {
+    pub audio: u32,
+    pub video: u32,
+    pub rtx: u32,
+    /// Video layers the SSRCs are laid out for, between 1 and `MAX_SIMULCAST_LAYERS`
+    pub layers: u8,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SsrcConflict {
+    /// The voice connection's SSRC falls among the stream's
+    Voice(u32),
+    /// The client's audio SSRC falls among the stream's video ones
+    Audio(u32),
+}
+
+impl std::fmt::Display for SsrcConflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let str = match self {
+            SsrcConflict::Voice(ssrc) => format!("Voice SSRC {} is also used by the stream", ssrc),
+            SsrcConflict::Audio(ssrc) => format!("Audio SSRC {} is also used by the video", ssrc),
+        };
+        f.write_str(&str)
+    }
+}
+
+impl StreamSSRCs {
+    /// Lays out the SSRCs for a stream with `layers` video layers, clamped to what can be sent
+    pub fn allocate(
+        base_ssrc: u32,
+        audio_ssrc: Option<u32>,
+        voice_ssrc: u32,
+        layers: u8,
+    ) -> Result<Self, SsrcConflict> {
+        let layers = layers.clamp(1, MAX_SIMULCAST_LAYERS);
+        let ssrcs = StreamSSRCs {
+            audio: audio_ssrc.unwrap_or(base_ssrc),
+            video: base_ssrc.wrapping_add(1),
+            rtx: base_ssrc.wrapping_add(2),
+            layers,
+        };
+
+        // Every layer takes a video and an RTX SSRC after the base
+        let is_video = |ssrc: u32| (1..=2 * layers as u32).contains(&ssrc.wrapping_sub(base_ssrc));
+        if voice_ssrc == ssrcs.audio || is_video(voice_ssrc) {
+            return Err(SsrcConflict::Voice(voice_ssrc));
+        }
+        if is_video(ssrcs.audio) {
+            return Err(SsrcConflict::Audio(ssrcs.audio));
+        }
+
+        Ok(ssrcs)
+    }
+
+    /// SSRCs of a simulcast layer, layer 0 is the full-size video and every layer shares the audio
+    pub fn layer(&self, layer: u8) -> StreamSSRCs {
+        let offset = 2 * layer as u32;
+        StreamSSRCs {
+            audio: self.audio,
+            video: self.video.wrapping_add(offset),
+            rtx: self.rtx.wrapping_add(offset),
+            layers: self.layers,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn audio_video_rtx_follow_the_base() {
+        let ssrcs = StreamSSRCs::allocate(1000, None, 1, 1).unwrap();
+        assert_eq!(
+            ssrcs,
+            StreamSSRCs {
+                audio: 1000,
+                video: 1001,
+                rtx: 1002,
+                layers: 1,
+            }
+        );
+        assert_eq!(ssrcs.layer(0), ssrcs);
+    }
+
+    #[test]
+    fn layers_take_consecutive_video_rtx_pairs() {
+        let ssrcs = StreamSSRCs::allocate(1000, None, 1, 3).unwrap();
+        assert_eq!(
+            ssrcs.layer(1),
+            StreamSSRCs {
+                audio: 1000,
+                video: 1003,
+                rtx: 1004,
+                layers: 3,
+            }
+        );
+        assert_eq!(
+            ssrcs.layer(2),
+            StreamSSRCs {
+                audio: 1000,
+                video: 1005,
+                rtx: 1006,
+                layers: 3,
+            }
+        );
+    }
+
+    #[test]
+    fn wraps_at_the_end_of_the_range() {
+        let ssrcs = StreamSSRCs::allocate(u32::MAX, None, 1000, 2).unwrap();
+        assert_eq!(ssrcs.video, 0);
+        assert_eq!(ssrcs.rtx, 1);
+        assert_eq!(ssrcs.layer(1).rtx, 3);
+    }
+
+    #[test]
+    fn rejects_voice_ssrc_inside_the_stream() {
+        assert_eq!(
+            StreamSSRCs::allocate(1000, None, 1000, 1),
+            Err(SsrcConflict::Voice(1000))
+        );
+        assert_eq!(
+            StreamSSRCs::allocate(1000, None, 1002, 1),
+            Err(SsrcConflict::Voice(1002))
+        );
+        assert!(StreamSSRCs::allocate(1000, None, 1003, 1).is_ok());
+        assert_eq!(
+            StreamSSRCs::allocate(1000, None, 1003, 2),
+            Err(SsrcConflict::Voice(1003))
+        );
+        assert!(StreamSSRCs::allocate(1000, None, 999, 3).is_ok());
+    }
+
+    #[test]
+    fn layers_are_clamped() {
+        assert_eq!(StreamSSRCs::allocate(1000, None, 1, 0).unwrap().layers, 1);
+        let ssrcs = StreamSSRCs::allocate(1000, None, 1, u8::MAX).unwrap();
+        assert_eq!(ssrcs.layers, MAX_SIMULCAST_LAYERS);
+        // Only the clamped layers' SSRCs are reserved
+        assert!(StreamSSRCs::allocate(1000, None, 1007, u8::MAX).is_ok());
+    }
+
+    #[test]
+    fn uses_the_client_audio_ssrc() {
+        let ssrcs = StreamSSRCs::allocate(1000, Some(5000), 1, 1).unwrap();
+        assert_eq!(ssrcs.audio, 5000);
+        assert_eq!(ssrcs.video, 1001);
+        // The base is free when audio is elsewhere
+        assert!(StreamSSRCs::allocate(1000, Some(5000), 1000, 1).is_ok());
+        assert_eq!(
+            StreamSSRCs::allocate(1000, Some(5000), 5000, 1),
+            Err(SsrcConflict::Voice(5000))
+        );
+        assert_eq!(
+            StreamSSRCs::allocate(1000, Some(1002), 1, 1),
+            Err(SsrcConflict::Audio(1002))
+        );
+    }
+}
@@ -0,0 +1,311 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+use gst::{glib, prelude::*, Element};
+use serde_json::{Map, Value};
+
+use crate::socket::StreamStats;
+
+/// How many frames waiting in the encoder are tracked before the oldest are forgotten
+const MAX_TRACKED_FRAMES: usize = 120;
+
+/// Counters updated by pad probes and signals on the streaming threads
+#[derive(Default)]
+struct StatsCounters {
+    video_frames: AtomicU64,
+    video_bytes: AtomicU64,
+    audio_bytes: AtomicU64,
+    queue_overruns: AtomicU64,
+    audio_underruns: AtomicU64,
+    /// Frames the leaky queue in front of the video encoder threw away
+    dropped_frames: AtomicU64,
+    /// Moving average of the time frames spend in the video encoder, in microseconds
+    encode_latency_us: AtomicU64,
+    /// When frames entered the video encoder, keyed by PTS
+    encode_starts: Mutex<VecDeque<(gst::ClockTime, Instant)>>,
+}
+
+/// Totals at the last report, used to turn counters into rates
+struct Snapshot {
+    time: Instant,
+    video_frames: u64,
+    video_bytes: u64,
+    audio_bytes: u64,
+    layer_bytes: Vec<u64>,
+}
+
+/// Collects statistics from a running pipeline
+pub struct StatsCollector {
+    counters: Arc<StatsCounters>,
+    last: Mutex<Snapshot>,
+    streamer: Element,
+    /// Reports the frames it duplicated or dropped to keep the frame rate
+    video_rate: Option<Element>,
+    /// Encoded bytes of each extra simulcast layer, smallest last
+    layer_bytes: Vec<Arc<AtomicU64>>,
+}
+
+impl StatsCollector {
+    pub fn new(streamer: Element) -> Self {
+        StatsCollector {
+            counters: Arc::new(StatsCounters::default()),
+            last: Mutex::new(Snapshot {
+                time: Instant::now(),
+                video_frames: 0,
+                video_bytes: 0,
+                audio_bytes: 0,
+                layer_bytes: vec![],
+            }),
+            streamer,
+            video_rate: None,
+            layer_bytes: vec![],
+        }
+    }
+
+    /// Counts encoded frames and bytes and measures how long frames take to encode
+    pub fn watch_video_encoder(&self, encoder: &Element) -> Result<(), glib::BoolError> {
+        let counters = Arc::clone(&self.counters);
+        static_pad(encoder, "sink")?.add_probe(gst::PadProbeType::BUFFER, move |_, info| {
+            if let Some(gst::PadProbeData::Buffer(ref buffer)) = info.data {
+                if let Some(pts) = buffer.pts() {
+                    let mut starts = counters.encode_starts.lock().unwrap();
+                    if starts.len() >= MAX_TRACKED_FRAMES {
+                        starts.pop_front();
+                    }
+                    starts.push_back((pts, Instant::now()));
+                }
+            }
+            gst::PadProbeReturn::Ok
+        });
+
+        let counters = Arc::clone(&self.counters);
+        static_pad(encoder, "src")?.add_probe(gst::PadProbeType::BUFFER, move |_, info| {
+            if let Some(gst::PadProbeData::Buffer(ref buffer)) = info.data {
+                counters.video_frames.fetch_add(1, Ordering::Relaxed);
+                counters
+                    .video_bytes
+                    .fetch_add(buffer.size() as u64, Ordering::Relaxed);
+
+                if let Some(pts) = buffer.pts() {
+                    counters.record_encode_end(pts);
+                }
+            }
+            gst::PadProbeReturn::Ok
+        });
+
+        Ok(())
+    }
+
+    /// Counts the bytes of an extra simulcast layer's encoder, layers must be added in order
+    pub fn watch_layer_encoder(&mut self, encoder: &Element) -> Result<(), glib::BoolError> {
+        let bytes = Arc::new(AtomicU64::new(0));
+        let counter = Arc::clone(&bytes);
+        static_pad(encoder, "src")?.add_probe(gst::PadProbeType::BUFFER, move |_, info| {
+            if let Some(gst::PadProbeData::Buffer(ref buffer)) = info.data {
+                counter.fetch_add(buffer.size() as u64, Ordering::Relaxed);
+            }
+            gst::PadProbeReturn::Ok
+        });
+
+        self.layer_bytes.push(bytes);
+        Ok(())
+    }
+
+    /// Counts encoded audio bytes
+    pub fn watch_audio_encoder(&self, encoder: &Element) -> Result<(), glib::BoolError> {
+        let counters = Arc::clone(&self.counters);
+        static_pad(encoder, "src")?.add_probe(gst::PadProbeType::BUFFER, move |_, info| {
+            if let Some(gst::PadProbeData::Buffer(ref buffer)) = info.data {
+                counters
+                    .audio_bytes
+                    .fetch_add(buffer.size() as u64, Ordering::Relaxed);
+            }
+            gst::PadProbeReturn::Ok
+        });
+
+        Ok(())
+    }
+
+    /// Counts gaps in captured audio, the source marks buffers after lost samples as discontinuous
+    pub fn watch_audio_source(&self, source: &Element) -> Result<(), glib::BoolError> {
+        let counters = Arc::clone(&self.counters);
+        let first = AtomicBool::new(true);
+        static_pad(source, "src")?.add_probe(gst::PadProbeType::BUFFER, move |_, info| {
+            if let Some(gst::PadProbeData::Buffer(ref buffer)) = info.data {
+                // The first buffer is always discontinuous
+                let first = first.swap(false, Ordering::Relaxed);
+                if buffer.flags().contains(gst::BufferFlags::DISCONT) && !first {
+                    counters.audio_underruns.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            gst::PadProbeReturn::Ok
+        });
+
+        Ok(())
+    }
+
+    /// Counts frames a queue leaking downstream and bounded by buffers throws away
+    ///
+    /// Every overrun leaks the oldest frame, unless one left while the queue wasn't locked.
+    pub fn watch_frame_queue(&self, queue: &Element) {
+        let counters = Arc::clone(&self.counters);
+        queue.connect("overrun", false, move |values| {
+            let queue = values[0].get::<Element>().unwrap();
+            let level: u32 = queue.property("current-level-buffers");
+            if level >= queue.property::<u32>("max-size-buffers") {
+                counters.dropped_frames.fetch_add(1, Ordering::Relaxed);
+            }
+            None
+        });
+    }
+
+    /// Reads duplicated and dropped frames from a videorate element
+    pub fn watch_video_rate(&mut self, video_rate: &Element) {
+        self.video_rate = Some(video_rate.clone());
+    }
+
+    /// Counts how often a queue fills up
+    pub fn watch_queue(&self, queue: &Element) {
+        let counters = Arc::clone(&self.counters);
+        queue.connect("overrun", false, move |_| {
+            counters.queue_overruns.fetch_add(1, Ordering::Relaxed);
+            None
+        });
+    }
+
+    /// Gets the current statistics, rates are averaged since the last call
+    pub fn collect(&self) -> StreamStats {
+        let now = Instant::now();
+        let counters = &self.counters;
+        let current = Snapshot {
+            time: now,
+            video_frames: counters.video_frames.load(Ordering::Relaxed),
+            video_bytes: counters.video_bytes.load(Ordering::Relaxed),
+            audio_bytes: counters.audio_bytes.load(Ordering::Relaxed),
+            layer_bytes: self
+                .layer_bytes
+                .iter()
+                .map(|bytes| bytes.load(Ordering::Relaxed))
+                .collect(),
+        };
+
+        let mut last = self.last.lock().unwrap();
+        let seconds = now.duration_since(last.time).as_secs_f64();
+        let (fps, video_bitrate, audio_bitrate) = match seconds > 0.0 {
+            true => (
+                (current.video_frames - last.video_frames) as f64 / seconds,
+                ((current.video_bytes - last.video_bytes) as f64 * 8.0 / seconds) as u64,
+                ((current.audio_bytes - last.audio_bytes) as f64 * 8.0 / seconds) as u64,
+            ),
+            false => (0.0, 0, 0),
+        };
+        let layer_bitrates = current
+            .layer_bytes
+            .iter()
+            .enumerate()
+            .map(|(layer, bytes)| {
+                let last_bytes = last.layer_bytes.get(layer).copied().unwrap_or_default();
+                match seconds > 0.0 {
+                    true => ((bytes - last_bytes) as f64 * 8.0 / seconds) as u64,
+                    false => 0,
+                }
+            })
+            .collect();
+
+        let stats = StreamStats {
+            fps,
+            video_bitrate,
+            audio_bitrate,
+            layer_bitrates,
+            encode_latency_ms: counters.encode_latency_us.load(Ordering::Relaxed) as f64 / 1000.0,
+            frames_encoded: current.video_frames,
+            dropped_frames: counters.dropped_frames.load(Ordering::Relaxed),
+            duplicated_frames: self
+                .video_rate
+                .as_ref()
+                .map_or(0, |video_rate| video_rate.property::<u64>("duplicate")),
+            rate_dropped_frames: self
+                .video_rate
+                .as_ref()
+                .map_or(0, |video_rate| video_rate.property::<u64>("drop")),
+            queue_overruns: counters.queue_overruns.load(Ordering::Relaxed),
+            audio_underruns: counters.audio_underruns.load(Ordering::Relaxed),
+            streamer: self.streamer_stats(),
+        };
+        *last = current;
+
+        stats
+    }
+
+    /// Reads the streamer's own statistics, if it exposes any
+    fn streamer_stats(&self) -> Option<Value> {
+        self.streamer.find_property("stats")?;
+        let stats = self
+            .streamer
+            .property_value("stats")
+            .get::<gst::Structure>()
+            .ok()?;
+
+        Some(Value::Object(
+            stats
+                .iter()
+                .map(|(name, value)| (name.to_string(), value_to_json(value)))
+                .collect::<Map<String, Value>>(),
+        ))
+    }
+}
+
+impl StatsCounters {
+    /// Finds when a frame entered the encoder and folds its encode time into the average
+    fn record_encode_end(&self, pts: gst::ClockTime) {
+        let mut starts = self.encode_starts.lock().unwrap();
+        let index = match starts.iter().position(|(start_pts, _)| *start_pts == pts) {
+            Some(index) => index,
+            None => return,
+        };
+
+        // Frames before this one were dropped or reordered by the encoder
+        let (_, start) = starts.drain(..=index).last().unwrap();
+        let sample = start.elapsed().as_micros() as u64;
+
+        let average = self.encode_latency_us.load(Ordering::Relaxed);
+        let average = match average {
+            0 => sample,
+            average => (average * 7 + sample) / 8,
+        };
+        self.encode_latency_us.store(average, Ordering::Relaxed);
+    }
+}
+
+fn static_pad(element: &Element, name: &str) -> Result<gst::Pad, glib::BoolError> {
+    element
+        .static_pad(name)
+        .ok_or_else(|| glib::bool_error!("{} has no {} pad", element.name(), name))
+}
+
+/// Converts basic GLib values to JSON, anything else is described with its debug output
+fn value_to_json(value: &glib::SendValue) -> Value {
+    if let Ok(v) = value.get::<u64>() {
+        Value::from(v)
+    } else if let Ok(v) = value.get::<i64>() {
+        Value::from(v)
+    } else if let Ok(v) = value.get::<u32>() {
+        Value::from(v)
+    } else if let Ok(v) = value.get::<i32>() {
+        Value::from(v)
+    } else if let Ok(v) = value.get::<f64>() {
+        Value::from(v)
+    } else if let Ok(v) = value.get::<bool>() {
+        Value::from(v)
+    } else if let Ok(v) = value.get::<String>() {
+        Value::from(v)
+    } else {
+        Value::String(format!("{:?}", value))
+    }
+}
@@ -3,23 +3,199 @@ use std::{
 };
 
 // use async_std::{channel::Sender, task};
-use crate::{pid, xid};
-use image::ImageBuffer;
-use sysinfo::{PidExt, ProcessExt, SystemExt};
+use crate::{
+    pid,
+    socket::{CaptureTarget, MonitorInfo, PreviewFormat, PreviewOptions, WindowInfo},
+    xid,
+};
+use image::{
+    codecs::{
+        jpeg::JpegEncoder,
+        webp::{WebPEncoder, WebPQuality},
+    },
+    ColorType, Rgb, RgbImage,
+};
+use serde::Serialize;
+use sysinfo::{Pid, PidExt, ProcessExt, System, SystemExt};
+use tracing::warn;
 use xcb::{
+    randr,
+    shm,
+    xinput,
     res::{ClientIdMask, ClientIdSpec, QueryClientIds},
     x::{
-        self, GetGeometry, GetImage,
+        self, GetGeometry, GetImage, GetProperty, GetWindowAttributes, InternAtom, QueryTree,
+        TranslateCoordinates,
     },
+    Xid,
 };
 
 pub struct XServerHandle {
     connection: xcb::Connection,
-    /// List of PIDs that are related to Xorg
-    xorg_procs: Vec<pid>,
+    root: x::Window,
+    atoms: Atoms,
+    /// Whether the server supports MIT-SHM for faster screenshots
+    shm_available: bool,
+    /// Whether the server supports XInput 2, needed to see clicks in other clients' windows
+    xinput_available: bool,
+    /// Whether the server supports X-Resource, the most reliable way to find a window's PID
+    res_available: bool,
+}
+
+/// How a window's PID was found
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PidSource {
+    /// The X-Resource extension, reported by the server for local clients
+    XRes,
+    /// The `_NET_WM_PID` property set by the client
+    NetWmPid,
+    /// A child of the window resolved through one of the other methods
+    ChildWindow,
+    /// The only running process named after the window's `WM_CLASS`, found in the process list
+    ProcessName,
+}
+
+/// A rectangle, usually of the root window
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: i16,
+    pub y: i16,
+    pub width: u16,
+    pub height: u16,
+}
+
+impl Rect {
+    /// The overlapping part of two rectangles, if any
+    pub fn intersection(&self, other: &Rect) -> Option<Rect> {
+        let x = (self.x as i32).max(other.x as i32);
+        let y = (self.y as i32).max(other.y as i32);
+        let right = (self.x as i32 + self.width as i32).min(other.x as i32 + other.width as i32);
+        let bottom =
+            (self.y as i32 + self.height as i32).min(other.y as i32 + other.height as i32);
+
+        if right <= x || bottom <= y {
+            return None;
+        }
+
+        Some(Rect {
+            x: x as i16,
+            y: y as i16,
+            width: (right - x) as u16,
+            height: (bottom - y) as u16,
+        })
+    }
+
+    /// The smallest rectangle containing both rectangles
+    pub fn union(&self, other: &Rect) -> Rect {
+        let x = (self.x as i32).min(other.x as i32);
+        let y = (self.y as i32).min(other.y as i32);
+        let right = (self.x as i32 + self.width as i32).max(other.x as i32 + other.width as i32);
+        let bottom =
+            (self.y as i32 + self.height as i32).max(other.y as i32 + other.height as i32);
+
+        Rect {
+            x: x as i16,
+            y: y as i16,
+            width: (right - x) as u16,
+            height: (bottom - y) as u16,
+        }
+    }
+}
+
+/// What the video source captures
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureSource {
+    Window(xid),
+    /// Part of the root window, covers screens, monitors, and regions
+    Area(Rect),
+}
+
+#[derive(Debug)]
+pub enum CaptureTargetError {
+    X(xcb::Error),
+    NoMonitor(String),
+    OutOfBounds(Rect),
+}
+
+impl std::fmt::Display for CaptureTargetError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let str = match self {
+            CaptureTargetError::X(e) => format!("X error: {}", e),
+            CaptureTargetError::NoMonitor(name) => format!("No monitor named {}", name),
+            CaptureTargetError::OutOfBounds(area) => {
+                format!("Capture area {:?} is empty or outside of the screen", area)
+            }
+        };
+        f.write_str(&str)
+    }
+}
+
+impl From<xcb::Error> for CaptureTargetError {
+    fn from(error: xcb::Error) -> Self {
+        CaptureTargetError::X(error)
+    }
+}
+
+/// Changes to a watched window, or pointer activity while clicks are watched
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WindowEvent {
+    Destroyed(xid),
+    /// Hidden, including being minimized
+    Unmapped(xid),
+    Mapped(xid),
+    /// Also sent when the window moves or restacks
+    Resized { xid: xid, size: Size },
+    /// A pointer button was pressed, in root window coordinates
+    Clicked { x: i16, y: i16 },
+    /// A top-level window was mapped, unmapped, moved, restacked, or focused
+    StackingChanged,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PidResolution {
+    pub pid: pid,
+    pub source: PidSource,
+}
+
+/// Non-predefined atoms used for window properties
+struct Atoms {
+    net_active_window: x::Atom,
+    net_client_list: x::Atom,
+    net_client_list_stacking: x::Atom,
+    net_wm_name: x::Atom,
+    net_wm_pid: x::Atom,
+    utf8_string: x::Atom,
+}
+
+impl Atoms {
+    fn intern(conn: &xcb::Connection) -> Result<Self, xcb::Error> {
+        let intern = |name: &[u8]| {
+            conn.send_request(&InternAtom {
+                only_if_exists: false,
+                name,
+            })
+        };
+
+        let net_active_window = intern(b"_NET_ACTIVE_WINDOW");
+        let net_client_list = intern(b"_NET_CLIENT_LIST");
+        let net_client_list_stacking = intern(b"_NET_CLIENT_LIST_STACKING");
+        let net_wm_name = intern(b"_NET_WM_NAME");
+        let net_wm_pid = intern(b"_NET_WM_PID");
+        let utf8_string = intern(b"UTF8_STRING");
+
+        Ok(Atoms {
+            net_active_window: conn.wait_for_reply(net_active_window)?.atom(),
+            net_client_list: conn.wait_for_reply(net_client_list)?.atom(),
+            net_client_list_stacking: conn.wait_for_reply(net_client_list_stacking)?.atom(),
+            net_wm_name: conn.wait_for_reply(net_wm_name)?.atom(),
+            net_wm_pid: conn.wait_for_reply(net_wm_pid)?.atom(),
+            utf8_string: conn.wait_for_reply(utf8_string)?.atom(),
+        })
+    }
 }
 
-#[derive(PartialEq, Eq, Clone, Copy)]
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
 pub struct Size {
     pub width: u16,
     pub height: u16,
@@ -27,88 +203,1011 @@ pub struct Size {
 
 impl XServerHandle {
     pub fn new() -> Result<Self, xcb::Error> {
-        // Connect to the server
-        let (conn, _) = xcb::Connection::connect(None)?;
+        Self::connect(None)
+    }
 
-        // Get the current Xorg process to make sure XServer isn't falsely recognizing windows (cached)
-        let mut system = sysinfo::System::new();
-        system.refresh_processes();
-        let xorg_procs = system
-            .processes_by_name("Xorg")
-            .map(|p| p.pid().as_u32())
-            .collect();
+    /// Connects to a specific display, `None` uses `$DISPLAY`
+    fn connect(display: Option<&str>) -> Result<Self, xcb::Error> {
+        // Connect to the server
+        let (conn, screen_num) = xcb::Connection::connect_with_extensions(
+            display,
+            &[],
+            &[
+                xcb::Extension::Shm,
+                xcb::Extension::Input,
+                xcb::Extension::Res,
+            ],
+        )?;
+        let root = conn
+            .get_setup()
+            .roots()
+            .nth(screen_num as usize)
+            .expect("X server has no screen for this connection")
+            .root();
+        let atoms = Atoms::intern(&conn)?;
+        let shm_available = conn
+            .active_extensions()
+            .any(|e| e == xcb::Extension::Shm);
+        let xinput_available = match conn.active_extensions().any(|e| e == xcb::Extension::Input) {
+            true => {
+                // Raw events need XInput 2, which must be announced before selecting them
+                let cookie = conn.send_request(&xinput::XiQueryVersion {
+                    major_version: 2,
+                    minor_version: 0,
+                });
+                conn.wait_for_reply(cookie)?.major_version() >= 2
+            }
+            false => false,
+        };
+        let res_available = conn
+            .active_extensions()
+            .any(|e| e == xcb::Extension::Res);
 
         Ok(XServerHandle {
             connection: conn,
-            /*cache: HashMap::new(), last_cache_wipe: None,*/ xorg_procs,
+            root,
+            atoms,
+            shm_available,
+            xinput_available,
+            res_available,
         })
     }
 
-    /// Attempts to derive a PID from an XID
-    pub fn pid_from_xid(&self, xid: xid) -> Result<Option<pid>, xcb::Error> {
+    /// Attempts to derive a PID from an XID, trying each resolution method in turn
+    ///
+    /// `system` must have its processes refreshed, it's used to tell X servers apart from clients
+    /// and to look processes up by name.
+    pub fn pid_from_xid(
+        &self,
+        xid: xid,
+        system: &System,
+    ) -> Result<Option<PidResolution>, xcb::Error> {
+        let window: x::Window = unsafe { xcb::XidNew::new(xid) };
+
+        if let Some(resolution) = self.pid_from_window(window, system)? {
+            return Ok(Some(resolution));
+        }
+
+        // The XID may be a window manager frame or toolkit wrapper around the client's own window
+        for child in self.children(window)? {
+            if let Some(resolution) = self.pid_from_window(child, system)? {
+                return Ok(Some(PidResolution {
+                    pid: resolution.pid,
+                    source: PidSource::ChildWindow,
+                }));
+            }
+        }
+
+        Ok(self
+            .pid_from_process_name(window, system)?
+            .map(|pid| PidResolution {
+                pid,
+                source: PidSource::ProcessName,
+            }))
+    }
+
+    fn pid_from_window(
+        &self,
+        window: x::Window,
+        system: &System,
+    ) -> Result<Option<PidResolution>, xcb::Error> {
+        if let Some(pid) = self.pid_from_xres(window, system) {
+            return Ok(Some(PidResolution {
+                pid,
+                source: PidSource::XRes,
+            }));
+        }
+
+        if let Some(pid) = self.pid_from_net_wm_pid(window)? {
+            return Ok(Some(PidResolution {
+                pid,
+                source: PidSource::NetWmPid,
+            }));
+        }
+
+        Ok(None)
+    }
+
+    /// Asks the server which local process owns the window, only works for local clients
+    ///
+    /// Failures aren't errors, the server may not support the extension or know the client's PID.
+    fn pid_from_xres(&self, window: x::Window, system: &System) -> Option<pid> {
+        if !self.res_available {
+            return None;
+        }
+
         // Create request
         let cookie = self.connection.send_request(&QueryClientIds {
             specs: &[ClientIdSpec {
-                client: xid,
+                client: window.resource_id(),
                 mask: ClientIdMask::LOCAL_CLIENT_PID,
             }],
         });
 
+        let reply = self.connection.wait_for_reply(cookie).ok()?;
+
+        // Windows owned by the server itself (root, Xwayland's own windows) resolve to the server's PID
+        reply
+            .ids()
+            .find_map(|id| id.value().first().copied())
+            .filter(|pid| !is_x_server_process(system, *pid))
+    }
+
+    /// Reads the PID the client reports for itself, only trusted if the client runs on this machine
+    fn pid_from_net_wm_pid(&self, window: x::Window) -> Result<Option<pid>, xcb::Error> {
+        let cookie = self.connection.send_request(&GetProperty {
+            delete: false,
+            window,
+            property: self.atoms.net_wm_pid,
+            r#type: x::ATOM_CARDINAL,
+            long_offset: 0,
+            long_length: 1,
+        });
+
         let reply = self.connection.wait_for_reply(cookie)?;
+        if reply.format() != 32 {
+            return Ok(None);
+        }
+
+        let pid = match reply.value::<u32>().first() {
+            Some(pid) => *pid,
+            None => return Ok(None),
+        };
+
+        let machine = self.property_bytes(window, x::ATOM_WM_CLIENT_MACHINE, x::ATOM_STRING)?;
+        if !machine.is_empty() && !is_local_hostname(&String::from_utf8_lossy(&machine)) {
+            return Ok(None);
+        }
+
+        Ok(Some(pid))
+    }
 
-        if let Some(val) = reply.ids().next() {
-            return Ok(
-                if !val.value().is_empty() && !self.xorg_procs.iter().any(|v| *v == val.value()[0])
+    /// Last resort for clients that can't be asked, looks for a single process named after the window's class
+    fn pid_from_process_name(
+        &self,
+        window: x::Window,
+        system: &System,
+    ) -> Result<Option<pid>, xcb::Error> {
+        for name in self.wm_class(window)? {
+            // The kernel truncates process names to 15 bytes
+            let name = name.as_bytes();
+            let name = &name[..name.len().min(15)];
+            let matches =
+                |process: &sysinfo::Process| process.name().as_bytes().eq_ignore_ascii_case(name);
+
+            // Multi-process clients spawn helpers with the same name, only count the topmost one
+            let mut found = system.processes().values().filter(|process| {
+                matches(process)
+                    && !is_x_server_process(system, process.pid().as_u32())
+                    && !process
+                        .parent()
+                        .and_then(|parent| system.process(parent))
+                        .map_or(false, matches)
+            });
+
+            if let (Some(process), None) = (found.next(), found.next()) {
+                return Ok(Some(process.pid().as_u32()));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Lists top-level client windows from `_NET_CLIENT_LIST`, or the window tree if the window manager doesn't provide it
+    pub fn list_windows(&self) -> Result<Vec<WindowInfo>, xcb::Error> {
+        let mut windows = self.client_list()?;
+        if windows.is_empty() {
+            windows = self.tree_client_windows()?;
+        }
+
+        let mut system = System::new();
+        system.refresh_processes();
+
+        // Windows can disappear while being queried, skip those
+        Ok(windows
+            .into_iter()
+            .filter_map(|window| self.window_info(window, &system).ok())
+            .collect())
+    }
+
+    fn client_list(&self) -> Result<Vec<x::Window>, xcb::Error> {
+        let cookie = self.connection.send_request(&GetProperty {
+            delete: false,
+            window: self.root,
+            property: self.atoms.net_client_list,
+            r#type: x::ATOM_WINDOW,
+            long_offset: 0,
+            long_length: u32::MAX,
+        });
+
+        Ok(self.connection.wait_for_reply(cookie)?.value().to_vec())
+    }
+
+    /// Finds client windows among the root's children, looking one level down for windows reparented into frames
+    fn tree_client_windows(&self) -> Result<Vec<x::Window>, xcb::Error> {
+        let mut windows = vec![];
+        for child in self.children(self.root)? {
+            if self.has_wm_class(child)? {
+                windows.push(child);
+                continue;
+            }
+
+            for grandchild in self.children(child)? {
+                if self.has_wm_class(grandchild)? {
+                    windows.push(grandchild);
+                }
+            }
+        }
+
+        Ok(windows)
+    }
+
+    fn children(&self, window: x::Window) -> Result<Vec<x::Window>, xcb::Error> {
+        let cookie = self.connection.send_request(&QueryTree { window });
+        Ok(self.connection.wait_for_reply(cookie)?.children().to_vec())
+    }
+
+    fn has_wm_class(&self, window: x::Window) -> Result<bool, xcb::Error> {
+        Ok(!self
+            .property_bytes(window, x::ATOM_WM_CLASS, x::ATOM_STRING)?
+            .is_empty())
+    }
+
+    /// Reads `WM_CLASS`, two null-terminated strings, instance then class
+    fn wm_class(&self, window: x::Window) -> Result<Vec<String>, xcb::Error> {
+        Ok(self
+            .property_bytes(window, x::ATOM_WM_CLASS, x::ATOM_STRING)?
+            .split(|b| *b == 0)
+            .filter(|s| !s.is_empty())
+            .map(|s| String::from_utf8_lossy(s).to_string())
+            .collect())
+    }
+
+    fn property_bytes(
+        &self,
+        window: x::Window,
+        property: x::Atom,
+        r#type: x::Atom,
+    ) -> Result<Vec<u8>, xcb::Error> {
+        let cookie = self.connection.send_request(&GetProperty {
+            delete: false,
+            window,
+            property,
+            r#type,
+            long_offset: 0,
+            long_length: u32::MAX,
+        });
+
+        let reply = self.connection.wait_for_reply(cookie)?;
+        // Other clients control property formats, don't trust them
+        if reply.format() != 8 {
+            return Ok(vec![]);
+        }
+
+        Ok(reply.value().to_vec())
+    }
+
+    /// Gets the title of a window, preferring the UTF-8 `_NET_WM_NAME`
+    fn window_title(&self, window: x::Window) -> Result<Option<String>, xcb::Error> {
+        let mut title = self.property_bytes(window, self.atoms.net_wm_name, self.atoms.utf8_string)?;
+        if title.is_empty() {
+            title = self.property_bytes(window, x::ATOM_WM_NAME, x::ATOM_ANY)?;
+        }
+
+        Ok(if title.is_empty() {
+            None
+        } else {
+            Some(String::from_utf8_lossy(&title).to_string())
+        })
+    }
+
+    fn window_info(&self, window: x::Window, system: &System) -> Result<WindowInfo, xcb::Error> {
+        let xid = window.resource_id();
+
+        let attributes_cookie = self
+            .connection
+            .send_request(&GetWindowAttributes { window });
+        let geometry_cookie = self.connection.send_request(&GetGeometry {
+            drawable: x::Drawable::Window(window),
+        });
+        let position_cookie = self.connection.send_request(&TranslateCoordinates {
+            src_window: window,
+            dst_window: self.root,
+            src_x: 0,
+            src_y: 0,
+        });
+
+        let attributes = self.connection.wait_for_reply(attributes_cookie)?;
+        let geometry = self.connection.wait_for_reply(geometry_cookie)?;
+        let position = self.connection.wait_for_reply(position_cookie)?;
+
+        let mut wm_class = self.wm_class(window)?.into_iter();
+
+        let pid = self.pid_from_xid(xid, system)?;
+
+        Ok(WindowInfo {
+            xid,
+            title: self.window_title(window)?,
+            instance: wm_class.next(),
+            class: wm_class.next(),
+            pid: pid.map(|p| p.pid),
+            pid_source: pid.map(|p| p.source),
+            x: position.dst_x(),
+            y: position.dst_y(),
+            width: geometry.width(),
+            height: geometry.height(),
+            visible: attributes.map_state() == x::MapState::Viewable,
+        })
+    }
+
+    /// Starts receiving structure events (destroy, map, unmap, resize) for a window
+    pub fn watch_window(&self, xid: xid) -> Result<(), xcb::Error> {
+        self.set_event_mask(xid, x::EventMask::STRUCTURE_NOTIFY)
+    }
+
+    /// Stops receiving events for a window
+    pub fn unwatch_window(&self, xid: xid) -> Result<(), xcb::Error> {
+        self.set_event_mask(xid, x::EventMask::NO_EVENT)
+    }
+
+    /// Starts or stops following top-level window changes and focus, used by the privacy shield
+    pub fn watch_stacking(&self, watch: bool) -> Result<(), xcb::Error> {
+        let mask = match watch {
+            true => x::EventMask::SUBSTRUCTURE_NOTIFY | x::EventMask::PROPERTY_CHANGE,
+            false => x::EventMask::NO_EVENT,
+        };
+        self.set_event_mask(self.root.resource_id(), mask)
+    }
+
+    /// Finds the parts of a capture covered by visible windows whose `WM_CLASS` matches
+    /// any of the patterns, in capture coordinates
+    pub fn protected_regions(
+        &self,
+        source: CaptureSource,
+        patterns: &[String],
+    ) -> Result<Vec<Rect>, xcb::Error> {
+        if patterns.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let (captured, capture_area) = match source {
+            CaptureSource::Window(xid) => {
+                let window = unsafe { xcb::XidNew::new(xid) };
+                (Some(self.top_level(window)?), self.root_rect(window)?)
+            }
+            CaptureSource::Area(area) => (None, area),
+        };
+
+        // Children of the root are in stacking order, bottom to top
+        let stack = self.children(self.root)?;
+        // Only windows stacked above a captured window can cover it, including itself
+        let start = captured
+            .and_then(|captured| stack.iter().position(|w| *w == captured))
+            .unwrap_or(0);
+
+        let mut regions = vec![];
+        for &window in &stack[start..] {
+            let cookie = self
+                .connection
+                .send_request(&GetWindowAttributes { window });
+            if self.connection.wait_for_reply(cookie)?.map_state() != x::MapState::Viewable {
+                continue;
+            }
+
+            if !self.is_protected(window, patterns)? {
+                continue;
+            }
+
+            if let Some(overlap) = self.root_rect(window)?.intersection(&capture_area) {
+                regions.push(Rect {
+                    x: overlap.x - capture_area.x,
+                    y: overlap.y - capture_area.y,
+                    width: overlap.width,
+                    height: overlap.height,
+                });
+            }
+        }
+
+        Ok(regions)
+    }
+
+    /// Checks a top-level window and the client windows a window manager may have reparented into it
+    fn is_protected(&self, window: x::Window, patterns: &[String]) -> Result<bool, xcb::Error> {
+        let matches = |window| -> Result<bool, xcb::Error> {
+            Ok(self
+                .wm_class(window)?
+                .iter()
+                .any(|name| patterns.iter().any(|p| matches_pattern(p, name))))
+        };
+
+        if matches(window)? {
+            return Ok(true);
+        }
+
+        for child in self.children(window)? {
+            if matches(child)? {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Finds the child of the root window containing a window
+    fn top_level(&self, mut window: x::Window) -> Result<x::Window, xcb::Error> {
+        loop {
+            let cookie = self.connection.send_request(&QueryTree { window });
+            let parent = self.connection.wait_for_reply(cookie)?.parent();
+            if parent == self.root || parent.is_none() {
+                return Ok(window);
+            }
+            window = parent;
+        }
+    }
+
+    /// Gets a window's area in root window coordinates
+    fn root_rect(&self, window: x::Window) -> Result<Rect, xcb::Error> {
+        let geometry_cookie = self.connection.send_request(&GetGeometry {
+            drawable: x::Drawable::Window(window),
+        });
+        let position_cookie = self.connection.send_request(&TranslateCoordinates {
+            src_window: window,
+            dst_window: self.root,
+            src_x: 0,
+            src_y: 0,
+        });
+
+        let geometry = self.connection.wait_for_reply(geometry_cookie)?;
+        let position = self.connection.wait_for_reply(position_cookie)?;
+
+        Ok(Rect {
+            x: position.dst_x(),
+            y: position.dst_y(),
+            width: geometry.width(),
+            height: geometry.height(),
+        })
+    }
+
+    /// Starts or stops receiving pointer button presses anywhere on the screen,
+    /// returns false if the server can't report them
+    pub fn watch_clicks(&self, watch: bool) -> Result<bool, xcb::Error> {
+        if !self.xinput_available {
+            return Ok(false);
+        }
+
+        let mask = match watch {
+            true => xinput::XiEventMask::RAW_BUTTON_PRESS,
+            false => xinput::XiEventMask::empty(),
+        };
+        self.connection
+            .send_and_check_request(&xinput::XiSelectEvents {
+                window: self.root,
+                masks: &[xinput::EventMaskBuf::new(xinput::Device::AllMaster, &[mask])],
+            })?;
+        Ok(true)
+    }
+
+    /// Converts a root window position to a position within a capture, if it lies inside it
+    pub fn capture_position(
+        &self,
+        source: CaptureSource,
+        x: i16,
+        y: i16,
+    ) -> Result<Option<(i32, i32)>, xcb::Error> {
+        let (x, y, size) = match source {
+            CaptureSource::Window(xid) => {
+                let window = unsafe { xcb::XidNew::new(xid) };
+                let cookie = self.connection.send_request(&TranslateCoordinates {
+                    src_window: self.root,
+                    dst_window: window,
+                    src_x: x,
+                    src_y: y,
+                });
+                let reply = self.connection.wait_for_reply(cookie)?;
+                (
+                    reply.dst_x() as i32,
+                    reply.dst_y() as i32,
+                    window_size(&self.connection, xid)?,
+                )
+            }
+            CaptureSource::Area(area) => (
+                x as i32 - area.x as i32,
+                y as i32 - area.y as i32,
+                Size {
+                    width: area.width,
+                    height: area.height,
+                },
+            ),
+        };
+
+        let inside = (0..size.width as i32).contains(&x) && (0..size.height as i32).contains(&y);
+        Ok(inside.then_some((x, y)))
+    }
+
+    fn set_event_mask(&self, xid: xid, mask: x::EventMask) -> Result<(), xcb::Error> {
+        self.connection
+            .send_and_check_request(&x::ChangeWindowAttributes {
+                window: unsafe { xcb::XidNew::new(xid) },
+                value_list: &[x::Cw::EventMask(mask)],
+            })?;
+        Ok(())
+    }
+
+    /// Drains pending events for watched windows without blocking
+    pub fn poll_window_events(&self) -> Result<Vec<WindowEvent>, xcb::Error> {
+        let mut events = vec![];
+        while let Some(event) = self.connection.poll_for_event()? {
+            let event = match event {
+                // Substructure events for children of the root, while stacking is watched
+                xcb::Event::X(x::Event::DestroyNotify(ev)) if ev.event() == self.root => {
+                    WindowEvent::StackingChanged
+                }
+                xcb::Event::X(x::Event::UnmapNotify(ev)) if ev.event() == self.root => {
+                    WindowEvent::StackingChanged
+                }
+                xcb::Event::X(x::Event::MapNotify(ev)) if ev.event() == self.root => {
+                    WindowEvent::StackingChanged
+                }
+                xcb::Event::X(x::Event::ConfigureNotify(ev)) if ev.event() == self.root => {
+                    WindowEvent::StackingChanged
+                }
+                xcb::Event::X(x::Event::ReparentNotify(_) | x::Event::CirculateNotify(_)) => {
+                    WindowEvent::StackingChanged
+                }
+                xcb::Event::X(x::Event::PropertyNotify(ev))
+                    if ev.window() == self.root
+                        && (ev.atom() == self.atoms.net_active_window
+                            || ev.atom() == self.atoms.net_client_list_stacking) =>
                 {
-                    Some(val.value()[0])
-                } else {
-                    None
+                    WindowEvent::StackingChanged
+                }
+                xcb::Event::X(x::Event::DestroyNotify(ev)) => {
+                    WindowEvent::Destroyed(ev.window().resource_id())
+                }
+                xcb::Event::X(x::Event::UnmapNotify(ev)) => {
+                    WindowEvent::Unmapped(ev.window().resource_id())
+                }
+                xcb::Event::X(x::Event::MapNotify(ev)) => {
+                    WindowEvent::Mapped(ev.window().resource_id())
+                }
+                xcb::Event::X(x::Event::ConfigureNotify(ev)) => WindowEvent::Resized {
+                    xid: ev.window().resource_id(),
+                    size: Size {
+                        width: ev.width(),
+                        height: ev.height(),
+                    },
                 },
-            );
+                // Scroll wheel "buttons" aren't clicks
+                xcb::Event::Input(xinput::Event::RawButtonPress(ev)) if ev.detail() <= 3 => {
+                    // Raw events aren't tied to a window, so ask where the pointer is
+                    let cookie = self.connection.send_request(&x::QueryPointer { window: self.root });
+                    let pointer = self.connection.wait_for_reply(cookie)?;
+                    WindowEvent::Clicked {
+                        x: pointer.root_x(),
+                        y: pointer.root_y(),
+                    }
+                }
+                _ => continue,
+            };
+
+            events.push(event);
         }
 
-        Ok(None)
+        Ok(events)
     }
 
-    pub fn take_screenshot(&self, xid: xid) -> Result<Vec<u8>, xcb::Error> {
-        let size = window_size(&self.connection, xid)?;
+    /// Lists RandR monitors with their position on the root window
+    pub fn list_monitors(&self) -> Result<Vec<MonitorInfo>, xcb::Error> {
+        let cookie = self.connection.send_request(&randr::GetMonitors {
+            window: self.root,
+            get_active: true,
+        });
+        let reply = self.connection.wait_for_reply(cookie)?;
 
-        let cookie = self.connection.send_request(&GetImage {
-            format: x::ImageFormat::ZPixmap, // jpg
-            drawable: xcb::x::Drawable::Window(unsafe { xcb::XidNew::new(xid) }),
+        reply
+            .monitors()
+            .map(|monitor| {
+                let name_cookie = self
+                    .connection
+                    .send_request(&x::GetAtomName { atom: monitor.name() });
+                let name = self.connection.wait_for_reply(name_cookie)?;
+
+                Ok(MonitorInfo {
+                    name: name.name().to_utf8().to_string(),
+                    x: monitor.x(),
+                    y: monitor.y(),
+                    width: monitor.width(),
+                    height: monitor.height(),
+                    primary: monitor.primary(),
+                })
+            })
+            .collect()
+    }
+
+    /// Turns a requested capture target into a concrete source
+    pub fn resolve_capture_target(
+        &self,
+        xid: xid,
+        target: &CaptureTarget,
+    ) -> Result<CaptureSource, CaptureTargetError> {
+        let root_size = window_size(&self.connection, self.root.resource_id())?;
+        let root = Rect {
             x: 0,
             y: 0,
-            width: size.width,
-            height: size.height,
-            plane_mask: u32::MAX,
-        });
+            width: root_size.width,
+            height: root_size.height,
+        };
 
-        let reply = self.connection.wait_for_reply(cookie)?;
+        let area = match target {
+            CaptureTarget::Window => return Ok(CaptureSource::Window(xid)),
+            CaptureTarget::Screen => root,
+            CaptureTarget::Monitor { name } => {
+                let monitor = self
+                    .list_monitors()?
+                    .into_iter()
+                    .find(|m| &m.name == name)
+                    .ok_or_else(|| CaptureTargetError::NoMonitor(name.clone()))?;
 
-        let mut buf: Cursor<Vec<u8>> = Cursor::new(Vec::new());
+                Rect {
+                    x: monitor.x,
+                    y: monitor.y,
+                    width: monitor.width,
+                    height: monitor.height,
+                }
+            }
+            CaptureTarget::Region {
+                x,
+                y,
+                width,
+                height,
+            } => Rect {
+                x: *x,
+                y: *y,
+                width: *width,
+                height: *height,
+            },
+        };
 
-        let mut image: ImageBuffer<image::Rgba<u8>, _> = ImageBuffer::from_raw(
-            size.width.into(),
-            size.height.into(),
-            reply.data().to_owned(),
-        )
-        .unwrap();
-        // Convert BGRA to RGBA
-        for pixel in image.pixels_mut() {
-            pixel.0 = [pixel.0[2], pixel.0[1], pixel.0[0], pixel.0[3]];
+        if area.width == 0
+            || area.height == 0
+            || area.x < 0
+            || area.y < 0
+            || area.x as u32 + area.width as u32 > root.width as u32
+            || area.y as u32 + area.height as u32 > root.height as u32
+        {
+            return Err(CaptureTargetError::OutOfBounds(area));
         }
 
-        let (width, height) = calculate_aspect_ratio_fit(image.width(), image.height(), 512, 512);
+        Ok(CaptureSource::Area(area))
+    }
+
+    /// Takes a thumbnail of a capture source
+    pub fn take_screenshot(
+        &self,
+        source: CaptureSource,
+        options: &PreviewOptions,
+    ) -> Result<Vec<u8>, ScreenshotError> {
+        let (window, area) = match source {
+            CaptureSource::Window(xid) => {
+                let size = window_size(&self.connection, xid)?;
+                (
+                    unsafe { xcb::XidNew::new(xid) },
+                    Rect {
+                        x: 0,
+                        y: 0,
+                        width: size.width,
+                        height: size.height,
+                    },
+                )
+            }
+            CaptureSource::Area(area) => (self.root, area),
+        };
+
+        let image = match self.shm_available {
+            true => match self.get_image_shm(window, area) {
+                Ok(image) => image,
+                Err(e) => {
+                    warn!("Shared memory screenshot failed, falling back to GetImage: {}", e);
+                    self.get_image(window, area)?
+                }
+            },
+            false => self.get_image(window, area)?,
+        };
+
+        let (width, height) = calculate_aspect_ratio_fit(
+            image.width(),
+            image.height(),
+            options.max_width.get(),
+            options.max_height.get(),
+        );
 
         // Resize image to reasonable thumbnail size
         let image =
             image::imageops::resize(&image, width, height, image::imageops::FilterType::Triangle);
-        image.write_to(&mut buf, image::ImageFormat::Jpeg).unwrap();
+
+        let mut buf: Cursor<Vec<u8>> = Cursor::new(Vec::new());
+        match options.format {
+            PreviewFormat::Jpeg => {
+                JpegEncoder::new_with_quality(&mut buf, options.quality.clamp(1, 100))
+                    .encode_image(&image)?
+            }
+            PreviewFormat::Png => image.write_to(&mut buf, image::ImageFormat::Png)?,
+            PreviewFormat::Webp => WebPEncoder::new_with_quality(
+                &mut buf,
+                WebPQuality::lossy(options.quality.min(100)),
+            )
+            .encode(image.as_raw(), image.width(), image.height(), ColorType::Rgb8)?,
+        }
 
         Ok(buf.into_inner())
     }
+
+    /// Reads an image over the X socket
+    fn get_image(&self, window: x::Window, area: Rect) -> Result<RgbImage, ScreenshotError> {
+        let cookie = self.connection.send_request(&GetImage {
+            format: x::ImageFormat::ZPixmap,
+            drawable: x::Drawable::Window(window),
+            x: area.x,
+            y: area.y,
+            width: area.width,
+            height: area.height,
+            plane_mask: u32::MAX,
+        });
+
+        let reply = self.connection.wait_for_reply(cookie)?;
+
+        self.convert_image(reply.data(), area, reply.depth(), reply.visual())
+    }
+
+    /// Reads an image through a MIT-SHM segment, avoiding copying the pixels over the socket
+    fn get_image_shm(&self, window: x::Window, area: Rect) -> Result<RgbImage, ScreenshotError> {
+        // Enough for the largest pixel format
+        let len = area.width as usize * area.height as usize * 4;
+
+        let segment = ShmSegment::new(len).ok_or(ScreenshotError::Shm)?;
+        let seg: shm::Seg = self.connection.generate_id();
+        self.connection.send_and_check_request(&shm::Attach {
+            shmseg: seg,
+            shmid: segment.id as u32,
+            read_only: false,
+        })?;
+
+        let cookie = self.connection.send_request(&shm::GetImage {
+            drawable: x::Drawable::Window(window),
+            x: area.x,
+            y: area.y,
+            width: area.width,
+            height: area.height,
+            plane_mask: u32::MAX,
+            format: x::ImageFormat::ZPixmap as u8,
+            shmseg: seg,
+            offset: 0,
+        });
+        let reply = self.connection.wait_for_reply(cookie);
+
+        self.connection
+            .send_and_check_request(&shm::Detach { shmseg: seg })?;
+
+        let reply = reply?;
+        let data = &segment.data()[..(reply.size() as usize).min(len)];
+
+        self.convert_image(data, area, reply.depth(), reply.visual())
+    }
+
+    /// Converts ZPixmap data of any true color visual to RGB
+    fn convert_image(
+        &self,
+        data: &[u8],
+        area: Rect,
+        depth: u8,
+        visual: x::Visualid,
+    ) -> Result<RgbImage, ScreenshotError> {
+        let setup = self.connection.get_setup();
+
+        let format = setup
+            .pixmap_formats()
+            .iter()
+            .find(|f| f.depth() == depth)
+            .ok_or(ScreenshotError::UnsupportedFormat(depth))?;
+
+        let visual = setup
+            .roots()
+            .flat_map(|screen| screen.allowed_depths())
+            .flat_map(|depth| depth.visuals())
+            .find(|v| v.visual_id() == visual)
+            .ok_or(ScreenshotError::UnsupportedFormat(depth))?;
+
+        let layout = PixelLayout {
+            bits_per_pixel: format.bits_per_pixel(),
+            scanline_pad: format.scanline_pad(),
+            msb_first: setup.image_byte_order() == x::ImageOrder::MsbFirst,
+            masks: [
+                visual.red_mask(),
+                visual.green_mask(),
+                visual.blue_mask(),
+            ],
+        };
+
+        zpixmap_to_rgb(data, area.width, area.height, &layout)
+            .ok_or(ScreenshotError::UnsupportedFormat(depth))
+    }
+}
+
+/// How the pixels of a ZPixmap image are stored
+struct PixelLayout {
+    bits_per_pixel: u8,
+    /// Bits each row is padded to
+    scanline_pad: u8,
+    msb_first: bool,
+    /// Red, green and blue masks of the visual
+    masks: [u32; 3],
+}
+
+/// Converts ZPixmap data to RGB, `None` if the layout isn't supported or the data is too short
+fn zpixmap_to_rgb(data: &[u8], width: u16, height: u16, layout: &PixelLayout) -> Option<RgbImage> {
+    let bytes_per_pixel = match layout.bits_per_pixel {
+        bpp @ (8 | 16 | 24 | 32) => bpp as usize / 8,
+        _ => return None,
+    };
+    let pad = (layout.scanline_pad as usize / 8).max(1);
+    let stride = (width as usize * bytes_per_pixel + pad - 1) / pad * pad;
+    if data.len() < stride * height as usize {
+        return None;
+    }
+
+    Some(RgbImage::from_fn(width.into(), height.into(), |x, y| {
+        let offset = y as usize * stride + x as usize * bytes_per_pixel;
+        let bytes = &data[offset..offset + bytes_per_pixel];
+        let pixel = if layout.msb_first {
+            bytes.iter().fold(0u32, |acc, b| (acc << 8) | *b as u32)
+        } else {
+            bytes.iter().rev().fold(0u32, |acc, b| (acc << 8) | *b as u32)
+        };
+
+        Rgb(layout.masks.map(|mask| scale_channel(pixel, mask)))
+    }))
+}
+
+/// Extracts a color channel and scales it to 8 bits
+fn scale_channel(pixel: u32, mask: u32) -> u8 {
+    if mask == 0 {
+        return 0;
+    }
+
+    let shift = mask.trailing_zeros();
+    let max = mask >> shift;
+    (((pixel & mask) >> shift) as u64 * 255 / max as u64) as u8
+}
+
+/// SysV shared memory segment, removed once dropped
+struct ShmSegment {
+    id: i32,
+    addr: *mut libc::c_void,
+    len: usize,
+}
+
+impl ShmSegment {
+    fn new(len: usize) -> Option<Self> {
+        let id = unsafe { libc::shmget(libc::IPC_PRIVATE, len, libc::IPC_CREAT | 0o600) };
+        if id < 0 {
+            return None;
+        }
+
+        let addr = unsafe { libc::shmat(id, std::ptr::null(), 0) };
+        if addr as isize == -1 {
+            unsafe { libc::shmctl(id, libc::IPC_RMID, std::ptr::null_mut()) };
+            return None;
+        }
+
+        Some(ShmSegment { id, addr, len })
+    }
+
+    fn data(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.addr as *const u8, self.len) }
+    }
+}
+
+impl Drop for ShmSegment {
+    fn drop(&mut self) {
+        unsafe {
+            libc::shmdt(self.addr);
+            libc::shmctl(self.id, libc::IPC_RMID, std::ptr::null_mut());
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ScreenshotError {
+    X(xcb::Error),
+    /// Unable to create a shared memory segment
+    Shm,
+    UnsupportedFormat(u8),
+    Encode(image::ImageError),
+}
+
+impl std::fmt::Display for ScreenshotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let str = match self {
+            ScreenshotError::X(e) => format!("X error: {}", e),
+            ScreenshotError::Shm => "Unable to create shared memory segment".to_string(),
+            ScreenshotError::UnsupportedFormat(depth) => {
+                format!("Unsupported image format for depth {}", depth)
+            }
+            ScreenshotError::Encode(e) => format!("Encoding error: {}", e),
+        };
+        f.write_str(&str)
+    }
+}
+
+impl From<xcb::Error> for ScreenshotError {
+    fn from(error: xcb::Error) -> Self {
+        ScreenshotError::X(error)
+    }
+}
+
+impl From<xcb::ProtocolError> for ScreenshotError {
+    fn from(error: xcb::ProtocolError) -> Self {
+        ScreenshotError::X(error.into())
+    }
+}
+
+impl From<image::ImageError> for ScreenshotError {
+    fn from(error: image::ImageError) -> Self {
+        ScreenshotError::Encode(error)
+    }
 }
 
+/// Case-insensitive match where `*` matches any run of characters
+fn matches_pattern(pattern: &str, value: &str) -> bool {
+    let pattern = pattern.to_lowercase();
+    let value = value.to_lowercase();
+
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let mut rest = match value.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+
+    let parts: Vec<&str> = parts.collect();
+    let (last, middle) = match parts.split_last() {
+        Some(split) => split,
+        // No wildcard, must be an exact match
+        None => return rest.is_empty(),
+    };
+
+    for part in middle {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+
+    rest.ends_with(last)
+}
+
+/// Checks if a PID belongs to an X server, checked against a fresh process list since the server can restart
+fn is_x_server_process(system: &System, pid: pid) -> bool {
+    system.process(Pid::from_u32(pid)).map_or(false, |p| {
+        matches!(p.name(), "Xorg" | "Xwayland" | "Xvfb" | "Xephyr" | "Xvnc" | "X")
+    })
+}
+
+/// Checks if a `WM_CLIENT_MACHINE` names this machine, an unreadable hostname proves nothing
+///
+/// The whole name must match, the same short name in another domain is another machine.
+fn is_local_hostname(name: &str) -> bool {
+    if name == "localhost" {
+        return true;
+    }
+
+    match std::fs::read_to_string("/proc/sys/kernel/hostname") {
+        Ok(hostname) => name.eq_ignore_ascii_case(hostname.trim()),
+        Err(_) => false,
+    }
+}
+
+/// Scales a size down to fit within the bounds, keeping its aspect ratio and at least one pixel
 fn calculate_aspect_ratio_fit(
     src_width: u32,
     src_height: u32,
@@ -116,13 +1215,16 @@ fn calculate_aspect_ratio_fit(
     max_height: u32,
 ) -> (u32, u32) {
     let ratio = f64::min(
-        max_width as f64 / src_width as f64,
-        max_height as f64 / src_height as f64,
+        1.0,
+        f64::min(
+            max_width as f64 / src_width as f64,
+            max_height as f64 / src_height as f64,
+        ),
     );
 
     (
-        (src_width as f64 * ratio).round() as u32,
-        (src_height as f64 * ratio).round() as u32,
+        ((src_width as f64 * ratio).round() as u32).max(1),
+        ((src_height as f64 * ratio).round() as u32).max(1),
     )
 }
 
@@ -138,3 +1240,299 @@ fn window_size(conn: &xcb::Connection, xid: xid) -> Result<Size, xcb::Error> {
         height: reply.height(),
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::{BufRead, BufReader},
+        process::{Child, Command, Stdio},
+    };
+
+    /// A private Xvfb server, killed when dropped
+    struct Xvfb {
+        process: Child,
+        display: String,
+    }
+
+    impl Xvfb {
+        /// Starts a server on a free display, `None` if Xvfb isn't installed
+        fn start() -> Option<Self> {
+            let mut process = match Command::new("Xvfb")
+                .args(["-displayfd", "1", "-nolisten", "tcp"])
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+            {
+                Ok(process) => process,
+                Err(e) => {
+                    eprintln!("Skipping, can't start Xvfb: {}", e);
+                    return None;
+                }
+            };
+
+            // The display number is written once the server accepts connections
+            let mut display = String::new();
+            BufReader::new(process.stdout.take().unwrap())
+                .read_line(&mut display)
+                .expect("Xvfb didn't report its display");
+
+            Some(Xvfb {
+                process,
+                display: format!(":{}", display.trim()),
+            })
+        }
+
+        fn connect(&self) -> XServerHandle {
+            XServerHandle::connect(Some(&self.display)).expect("Failed to connect to Xvfb")
+        }
+    }
+
+    impl Drop for Xvfb {
+        fn drop(&mut self) {
+            let _ = self.process.kill();
+            let _ = self.process.wait();
+        }
+    }
+
+    fn processes() -> System {
+        let mut system = System::new();
+        system.refresh_processes();
+        system
+    }
+
+    fn create_window(x: &XServerHandle) -> x::Window {
+        let window: x::Window = x.connection.generate_id();
+        x.connection
+            .send_and_check_request(&x::CreateWindow {
+                depth: x::COPY_FROM_PARENT as u8,
+                wid: window,
+                parent: x.root,
+                x: 0,
+                y: 0,
+                width: 10,
+                height: 10,
+                border_width: 0,
+                class: x::WindowClass::InputOutput,
+                visual: x::COPY_FROM_PARENT,
+                value_list: &[],
+            })
+            .unwrap();
+        window
+    }
+
+    fn set_property<P: x::PropEl>(
+        x: &XServerHandle,
+        window: x::Window,
+        property: x::Atom,
+        r#type: x::Atom,
+        data: &[P],
+    ) {
+        x.connection
+            .send_and_check_request(&x::ChangeProperty {
+                mode: x::PropMode::Replace,
+                window,
+                property,
+                r#type,
+                data,
+            })
+            .unwrap();
+    }
+
+    fn layout(bits_per_pixel: u8, msb_first: bool, masks: [u32; 3]) -> PixelLayout {
+        PixelLayout {
+            bits_per_pixel,
+            scanline_pad: 32,
+            msb_first,
+            masks,
+        }
+    }
+
+    #[test]
+    fn fit_scales_down_keeping_the_aspect_ratio() {
+        assert_eq!(calculate_aspect_ratio_fit(1920, 1080, 512, 512), (512, 288));
+        assert_eq!(calculate_aspect_ratio_fit(1080, 1920, 512, 512), (288, 512));
+        assert_eq!(calculate_aspect_ratio_fit(1920, 1080, 1920, 100), (178, 100));
+    }
+
+    #[test]
+    fn fit_never_upscales() {
+        assert_eq!(calculate_aspect_ratio_fit(640, 480, 65535, 65535), (640, 480));
+        assert_eq!(calculate_aspect_ratio_fit(640, 480, 640, 65535), (640, 480));
+    }
+
+    #[test]
+    fn fit_keeps_at_least_one_pixel() {
+        assert_eq!(calculate_aspect_ratio_fit(4000, 1, 100, 100), (100, 1));
+        assert_eq!(calculate_aspect_ratio_fit(1, 4000, 100, 100), (1, 100));
+    }
+
+    #[test]
+    fn scale_channel_extends_to_8_bits() {
+        // RGB565
+        assert_eq!(scale_channel(0xf800, 0xf800), 255);
+        assert_eq!(scale_channel(0x07e0, 0x07e0), 255);
+        assert_eq!(scale_channel(0x0400, 0x07e0), 129);
+        assert_eq!(scale_channel(0x001f, 0xf800), 0);
+        // 3-3-2
+        assert_eq!(scale_channel(0b0000_0011, 0b0000_0011), 255);
+        assert_eq!(scale_channel(0b1010_0000, 0b1110_0000), 182);
+        assert_eq!(scale_channel(0xff, 0), 0);
+    }
+
+    #[test]
+    fn converts_8_bpp() {
+        let layout = layout(8, false, [0b1110_0000, 0b0001_1100, 0b0000_0011]);
+        // Rows are padded to 4 bytes
+        let data = [0b1110_0000, 0b0001_1111, 0, 0, 0b0000_0011, 0xff, 0, 0];
+        let image = zpixmap_to_rgb(&data, 2, 2, &layout).unwrap();
+
+        assert_eq!(image.get_pixel(0, 0), &Rgb([255, 0, 0]));
+        assert_eq!(image.get_pixel(1, 0), &Rgb([0, 255, 255]));
+        assert_eq!(image.get_pixel(0, 1), &Rgb([0, 0, 255]));
+        assert_eq!(image.get_pixel(1, 1), &Rgb([255, 255, 255]));
+    }
+
+    #[test]
+    fn converts_16_bpp_in_both_byte_orders() {
+        let masks = [0xf800, 0x07e0, 0x001f];
+        let image = zpixmap_to_rgb(&[0x00, 0xf8, 0x1f, 0x00], 2, 1, &layout(16, false, masks));
+        let image = image.unwrap();
+        assert_eq!(image.get_pixel(0, 0), &Rgb([255, 0, 0]));
+        assert_eq!(image.get_pixel(1, 0), &Rgb([0, 0, 255]));
+
+        let image = zpixmap_to_rgb(&[0xf8, 0x00, 0x00, 0x1f], 2, 1, &layout(16, true, masks));
+        let image = image.unwrap();
+        assert_eq!(image.get_pixel(0, 0), &Rgb([255, 0, 0]));
+        assert_eq!(image.get_pixel(1, 0), &Rgb([0, 0, 255]));
+    }
+
+    #[test]
+    fn converts_24_bpp() {
+        let layout = layout(24, false, [0xff0000, 0x00ff00, 0x0000ff]);
+        // One pixel is 3 bytes, padded to 4
+        let data = [0x30, 0x20, 0x10, 0, 0x03, 0x02, 0x01, 0];
+        let image = zpixmap_to_rgb(&data, 1, 2, &layout).unwrap();
+
+        assert_eq!(image.get_pixel(0, 0), &Rgb([0x10, 0x20, 0x30]));
+        assert_eq!(image.get_pixel(0, 1), &Rgb([0x01, 0x02, 0x03]));
+    }
+
+    #[test]
+    fn converts_32_bpp() {
+        let layout = layout(32, false, [0xff0000, 0x00ff00, 0x0000ff]);
+        let image = zpixmap_to_rgb(&[0x30, 0x20, 0x10, 0xff], 1, 1, &layout).unwrap();
+
+        assert_eq!(image.get_pixel(0, 0), &Rgb([0x10, 0x20, 0x30]));
+    }
+
+    #[test]
+    fn rejects_unsupported_or_short_images() {
+        let masks = [0xff0000, 0x00ff00, 0x0000ff];
+        assert!(zpixmap_to_rgb(&[0; 16], 2, 2, &layout(4, false, masks)).is_none());
+        // Two padded 24 bpp rows need 16 bytes
+        assert!(zpixmap_to_rgb(&[0; 15], 2, 2, &layout(24, false, masks)).is_none());
+    }
+
+    #[test]
+    fn resolves_local_windows_through_xres() {
+        let Some(xvfb) = Xvfb::start() else { return };
+        let x = xvfb.connect();
+        let window = create_window(&x);
+
+        assert_eq!(
+            x.pid_from_xid(window.resource_id(), &processes()).unwrap(),
+            Some(PidResolution {
+                pid: std::process::id(),
+                source: PidSource::XRes,
+            })
+        );
+    }
+
+    #[test]
+    fn server_owned_windows_fall_back_to_children() {
+        let Some(xvfb) = Xvfb::start() else { return };
+        let x = xvfb.connect();
+        create_window(&x);
+
+        // The root belongs to the server, its only child to this process
+        assert_eq!(
+            x.pid_from_xid(x.root.resource_id(), &processes()).unwrap(),
+            Some(PidResolution {
+                pid: std::process::id(),
+                source: PidSource::ChildWindow,
+            })
+        );
+    }
+
+    #[test]
+    fn net_wm_pid_is_only_trusted_from_this_machine() {
+        let Some(xvfb) = Xvfb::start() else { return };
+        let x = xvfb.connect();
+        let window = create_window(&x);
+        set_property(&x, window, x.atoms.net_wm_pid, x::ATOM_CARDINAL, &[4242u32]);
+
+        assert_eq!(x.pid_from_net_wm_pid(window).unwrap(), Some(4242));
+
+        set_property(
+            &x,
+            window,
+            x::ATOM_WM_CLIENT_MACHINE,
+            x::ATOM_STRING,
+            b"tuxphones-remote.invalid",
+        );
+        assert_eq!(x.pid_from_net_wm_pid(window).unwrap(), None);
+
+        let hostname = std::fs::read_to_string("/proc/sys/kernel/hostname").unwrap();
+        set_property(
+            &x,
+            window,
+            x::ATOM_WM_CLIENT_MACHINE,
+            x::ATOM_STRING,
+            hostname.trim().as_bytes(),
+        );
+        assert_eq!(x.pid_from_net_wm_pid(window).unwrap(), Some(4242));
+    }
+
+    #[test]
+    fn process_name_needs_a_matching_wm_class() {
+        let Some(xvfb) = Xvfb::start() else { return };
+        let x = xvfb.connect();
+        let window = create_window(&x);
+        let system = processes();
+
+        assert_eq!(x.pid_from_process_name(window, &system).unwrap(), None);
+
+        // X servers are never the answer, even for a window claiming to be one
+        set_property(
+            &x,
+            window,
+            x::ATOM_WM_CLASS,
+            x::ATOM_STRING,
+            b"xvfb\0Xvfb\0",
+        );
+        assert_eq!(x.pid_from_process_name(window, &system).unwrap(), None);
+
+        // This test binary is the only process with its name
+        let own_pid = std::process::id();
+        let own_name = system.process(Pid::from_u32(own_pid)).unwrap().name();
+        let class = format!("{0}\0{0}\0", own_name);
+        set_property(&x, window, x::ATOM_WM_CLASS, x::ATOM_STRING, class.as_bytes());
+        assert_eq!(
+            x.pid_from_process_name(window, &system).unwrap(),
+            Some(own_pid)
+        );
+    }
+
+    #[test]
+    fn hostnames_must_match_in_full() {
+        assert!(is_local_hostname("localhost"));
+
+        let hostname = std::fs::read_to_string("/proc/sys/kernel/hostname").unwrap();
+        let hostname = hostname.trim();
+        assert!(is_local_hostname(hostname));
+        assert!(is_local_hostname(&hostname.to_uppercase()));
+        assert!(!is_local_hostname(&format!("{}.remote-domain", hostname)));
+        assert!(!is_local_hostname(""));
+    }
+}